@@ -0,0 +1,252 @@
+//! Application Load Balancer access logs: one space-separated line per
+//! request, with the free-text columns in double quotes.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use chrono::prelude::*;
+use serde_derive::Serialize;
+
+use {check_line_length, dash_as_none, output_value, parse_ip, parse_query, partition_keys_at, split_request, trim_line, url_path};
+use {geoip, useragent, LogError, Ndjson, RecordError, Timestamp, Transformed, OUTPUT_SIZE_RATIO};
+use config::Config;
+
+/// Columns every ALB line has; later ones were added over the years and
+/// are absent from older logs.
+static REQUIRED_FIELDS: usize = 13;
+
+#[derive(Serialize, Debug)]
+pub struct AlbLog<'a> {
+    #[serde(skip)]
+    pub time: DateTime<Utc>,
+    /// `http`, `https`, `h2`, `grpcs`, `ws` or `wss`.
+    #[serde(rename = "type")]
+    pub kind: &'a str,
+    #[serde(rename = "@timestamp")]
+    pub timestamp: Timestamp,
+    #[serde(rename = "@timestamp_utc")]
+    pub timestamp_utc: Timestamp,
+    /// Resource ID of the load balancer, e.g. `app/my-lb/50dc6c495c0c9188`.
+    pub elb: &'a str,
+    pub client_ip: IpAddr,
+    pub client_port: u16,
+    /// `None` when the request never reached a target.
+    pub target_ip: Option<IpAddr>,
+    pub target_port: Option<u16>,
+    /// Seconds, or `-1` when the load balancer couldn't dispatch the
+    /// request or the connection closed early.
+    pub request_processing_time: f64,
+    pub target_processing_time: f64,
+    pub response_processing_time: f64,
+    pub elb_status_code: u16,
+    pub target_status_code: Option<u16>,
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub request: &'a str,
+    pub method: Option<&'a str>,
+    pub url: Option<&'a str>,
+    pub protocol: Option<&'a str>,
+    pub query: BTreeMap<String, String>,
+    pub user_agent: Option<&'a str>,
+    pub ssl_cipher: Option<&'a str>,
+    pub ssl_protocol: Option<&'a str>,
+    pub target_group_arn: Option<&'a str>,
+    pub trace_id: Option<&'a str>,
+    pub domain_name: Option<&'a str>,
+    pub chosen_cert_arn: Option<&'a str>,
+    pub matched_rule_priority: Option<u32>,
+    pub request_creation_time: Option<Timestamp>,
+    pub actions_executed: Option<&'a str>,
+    pub redirect_url: Option<&'a str>,
+    pub error_reason: Option<&'a str>,
+    #[serde(rename = "target:port_list")]
+    pub target_port_list: Option<&'a str>,
+    pub target_status_code_list: Option<&'a str>,
+    pub classification: Option<&'a str>,
+    pub classification_reason: Option<&'a str>,
+    /// The line exactly as received, when `RETAIN_RAW` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<&'a str>,
+}
+
+/// Splits an ALB line on spaces, keeping quoted columns whole and without
+/// their quotes.
+fn split_fields(s: &str) -> Result<Vec<&str>, LogError> {
+    let mut fields = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let end = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut escaped = false;
+            let close = quoted.char_indices()
+                .find(|&(_, c)| {
+                    let found = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    found
+                })
+                .map(|(i, _)| i)
+                .ok_or_else(|| LogError::unmatched(s))?;
+            fields.push(&quoted[..close]);
+            close + 2
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            fields.push(&rest[..end]);
+            end
+        };
+        rest = rest[end..].trim_start();
+    }
+    Ok(fields)
+}
+
+/// Splits `ip:port`, whose address may be IPv6 without brackets.
+fn split_endpoint(s: &str) -> Option<(IpAddr, u16)> {
+    let i = s.rfind(':')?;
+    Some((parse_ip(&s[..i])?, s[i + 1..].parse().ok()?))
+}
+
+fn parse_utc(s: &str) -> Result<DateTime<Utc>, LogError> {
+    Ok(DateTime::parse_from_rfc3339(s)?.with_timezone(&Utc))
+}
+
+/// Parses one ALB access log line.
+pub fn parse_alb_log<'a>(config: &Config, raw: &'a str) -> Result<AlbLog<'a>, LogError> {
+    check_line_length(config, raw)?;
+    let s = trim_line(raw);
+    let xs = split_fields(s)?;
+    if xs.len() < REQUIRED_FIELDS {
+        return Err(LogError::unmatched(s));
+    }
+    let field = |i: usize| xs.get(i).cloned().and_then(dash_as_none);
+
+    let time = parse_utc(xs[1])?;
+    let (mut client_ip, client_port) = split_endpoint(xs[3]).ok_or_else(|| LogError::unmatched(s))?;
+    if config.anonymize_ip {
+        client_ip = geoip::anonymize(client_ip);
+    }
+    let target = field(4).and_then(split_endpoint);
+    let request = split_request(xs[12]).ok();
+
+    Ok(AlbLog {
+        time,
+        kind: xs[0],
+        timestamp: Timestamp::new(config.timestamp_format, &time),
+        timestamp_utc: Timestamp::new(config.timestamp_format, &time),
+        elb: xs[2],
+        client_ip,
+        client_port,
+        target_ip: target.map(|x| x.0),
+        target_port: target.map(|x| x.1),
+        request_processing_time: xs[5].parse()?,
+        target_processing_time: xs[6].parse()?,
+        response_processing_time: xs[7].parse()?,
+        elb_status_code: xs[8].parse()?,
+        target_status_code: field(9).map(|x| x.parse()).transpose()?,
+        received_bytes: xs[10].parse()?,
+        sent_bytes: xs[11].parse()?,
+        request: xs[12],
+        method: request.as_ref().map(|x| x.method),
+        url: request.as_ref().and_then(|x| x.path),
+        protocol: request.as_ref().and_then(|x| x.protocol),
+        query: request.as_ref().and_then(|x| x.path).map(parse_query).unwrap_or_default(),
+        user_agent: field(13),
+        ssl_cipher: field(14),
+        ssl_protocol: field(15),
+        target_group_arn: field(16),
+        trace_id: field(17),
+        domain_name: field(18),
+        chosen_cert_arn: field(19),
+        matched_rule_priority: field(20).map(|x| x.parse()).transpose()?,
+        request_creation_time: field(21).map(parse_utc).transpose()?.map(|x| Timestamp::new(config.timestamp_format, &x)),
+        actions_executed: field(22),
+        redirect_url: field(23),
+        error_reason: field(24),
+        target_port_list: field(25),
+        target_status_code_list: field(26),
+        classification: field(27),
+        classification_reason: field(28),
+        raw: if config.retain_raw { Some(raw) } else { None },
+    })
+}
+
+/// Health checks aren't logged by ALB, so only crawlers, paths matching
+/// `DROP_PATH_REGEX` and statuses outside `STATUS_FILTER` are dropped.
+fn should_drop(config: &Config, log: &AlbLog) -> bool {
+    (config.drop_crawlers && log.user_agent.and_then(useragent::parse).is_some_and(|x| x.is_crawler()))
+        || config.drop_path_regex.as_ref().is_some_and(|re| log.url.is_some_and(|x| re.is_match(&target_path(x))))
+        || !config.keeps_status(log.elb_status_code)
+}
+
+/// `url_path` of a URL as ALB logs it, with the scheme and host in front.
+fn target_path(url: &str) -> String {
+    let path = match url.find("://") {
+        Some(i) => url[i + 3..].find('/').map_or("/", |j| &url[i + 3 + j..]),
+        None => url,
+    };
+    url_path(path)
+}
+
+/// `transform_data` for ALB records.
+pub fn transform(config: &Config, s: &str) -> Result<Option<Transformed>, RecordError> {
+    let mut out = Ndjson::new(config, s.len() * OUTPUT_SIZE_RATIO);
+    for line in s.split('\n').filter(|x| !x.trim().is_empty()) {
+        match parse_alb_log(config, line) {
+            Ok(ref log) if should_drop(config, log) => {}
+            Ok(log) => out.push_line(line, output_value(config, &log), || partition_keys_at(&log.time, Some(log.elb_status_code)))?,
+            Err(error) => out.fail(error, line),
+        }
+    }
+    out.finish()
+}
+
+#[cfg(test)]
+static ALB_LOG: &str = r#"https 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.086 0.048 0.037 200 200 0 57 "GET https://www.example.com:443/search?q=rust HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337281-1d84f3d73c47ec4e58577259" "www.example.com" "arn:aws:acm:us-east-2:123456789012:certificate/12345678-1234-1234-1234-123456789012" 1 2018-07-02T22:22:48.364000Z "authenticate,forward" "-" "-" "10.0.0.1:80" "200" "-" "-""#;
+
+#[test]
+fn parse_alb_log_test() {
+    let log = parse_alb_log(&Config::default(), ALB_LOG).unwrap();
+    assert_eq!(log.kind, "https");
+    assert_eq!(log.timestamp, Timestamp::Text("2018-07-02T22:23:00.186641+00:00".to_string()));
+    assert_eq!(log.client_ip, "192.168.131.39".parse::<IpAddr>().unwrap());
+    assert_eq!(log.client_port, 2817);
+    assert_eq!(log.target_port, Some(80));
+    assert_eq!(log.elb_status_code, 200);
+    assert_eq!(log.method, Some("GET"));
+    assert_eq!(log.query["q"], "rust");
+    assert_eq!(log.user_agent, Some("curl/7.46.0"));
+    assert_eq!(log.matched_rule_priority, Some(1));
+    assert_eq!(log.actions_executed, Some("authenticate,forward"));
+    assert_eq!(log.redirect_url, None);
+    assert_eq!(log.classification, None);
+}
+
+#[test]
+fn parse_alb_log_unreachable_target_test() {
+    let line = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 [2001:db8::1]:2817 - -1 -1 -1 503 - 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0""#;
+    let log = parse_alb_log(&Config::default(), line).unwrap();
+    assert_eq!(log.client_ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+    assert_eq!(log.target_ip, None);
+    assert_eq!(log.target_status_code, None);
+    assert_eq!(log.request_processing_time, -1.0);
+    assert_eq!(log.trace_id, None);
+
+    assert_eq!(parse_alb_log(&Config::default(), "http 2018-07-02T22:23:00.186641Z").unwrap_err().kind(), "RegexParseError");
+}
+
+#[test]
+fn split_fields_test() {
+    assert_eq!(split_fields(r#"a "b c" "" "d \"e\"" f"#).unwrap(), vec!["a", "b c", "", r#"d \"e\""#, "f"]);
+    assert!(split_fields(r#"a "b"#).is_err());
+}
+
+#[test]
+fn transform_drop_path_test() {
+    let line = |url| ALB_LOG.replace("https://www.example.com:443/search?q=rust", url);
+    let config = Config::from_vars(|key| match key {
+        "DROP_PATH_REGEX" => Some(r"^/static/|\.css$".to_string()),
+        _ => None,
+    }).unwrap();
+
+    assert!(transform(&config, &line("https://www.example.com:443/static/app.js")).unwrap().is_none());
+    assert!(transform(&config, &line("https://www.example.com:443/site.css?v=2")).unwrap().is_none());
+    assert!(transform(&config, &line("https://www.example.com:443/search?q=a.css")).unwrap().is_some());
+    assert_eq!(target_path("http://www.example.com:80"), "/");
+}
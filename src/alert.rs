@@ -0,0 +1,133 @@
+//! Alerts for a Firehose invocation whose failed share of records passes
+//! `FAIL_ALERT_THRESHOLD`: always logged, and also published to SNS when
+//! `SNS_TOPIC_ARN` is set.
+
+use rusoto_core::Region;
+use rusoto_sns::{PublishInput, Sns, SnsClient};
+use serde_json::{self, Value};
+use tokio::runtime::{Builder, Runtime};
+
+use {LogError, Summary};
+use config::Config;
+
+/// Where alerts are published, so the check can run against a mock in
+/// tests.
+pub trait Notifier {
+    fn publish(&self, topic_arn: &str, message: &str) -> Result<(), LogError>;
+}
+
+/// `Notifier` backed by SNS, blocking on its own single-worker runtime.
+pub struct SnsNotifier {
+    runtime: Runtime,
+    client: SnsClient,
+}
+
+impl SnsNotifier {
+    /// Connects to SNS in the region from `AWS_REGION`.
+    pub fn new() -> Result<SnsNotifier, LogError> {
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+        let client = {
+            let _guard = runtime.enter();
+            SnsClient::new(Region::default())
+        };
+        Ok(SnsNotifier { runtime, client })
+    }
+}
+
+impl Notifier for SnsNotifier {
+    fn publish(&self, topic_arn: &str, message: &str) -> Result<(), LogError> {
+        let input = PublishInput {
+            topic_arn: Some(topic_arn.to_string()),
+            subject: Some("Firehose transform failures".to_string()),
+            message: message.to_string(),
+            ..Default::default()
+        };
+        self.runtime.block_on(self.client.publish(input))
+            .map_err(|e| LogError::SnsError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// The alert body: the invocation's counts and its failure rate.
+pub fn message(region: &str, summary: &Summary) -> Value {
+    serde_json::json!({
+        "region": region,
+        "ok": summary.ok,
+        "failed": summary.failed,
+        "dropped": summary.dropped,
+        "failure_rate": failure_rate(summary),
+    })
+}
+
+fn failure_rate(summary: &Summary) -> f64 {
+    let total = summary.ok + summary.failed + summary.dropped;
+    if total == 0 { 0.0 } else { summary.failed as f64 / total as f64 }
+}
+
+/// Raises an alert when `summary`'s failure rate is over the threshold,
+/// publishing it through `notifier` if a topic is configured. Returns
+/// whether it did.
+pub fn check(config: &Config, notifier: Option<&dyn Notifier>, region: &str, summary: &Summary) -> Result<bool, LogError> {
+    match config.fail_alert_threshold {
+        Some(threshold) if failure_rate(summary) > threshold => {}
+        _ => return Ok(false),
+    }
+
+    let message = message(region, summary);
+    tracing::error!(failed = summary.failed, "failure rate over FAIL_ALERT_THRESHOLD: {}", message);
+    if let (Some(topic_arn), Some(notifier)) = (config.sns_topic_arn.as_ref(), notifier) {
+        notifier.publish(topic_arn, &message.to_string())?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+struct MockNotifier {
+    published: ::std::cell::RefCell<Vec<(String, String)>>,
+}
+
+#[cfg(test)]
+impl Notifier for MockNotifier {
+    fn publish(&self, topic_arn: &str, message: &str) -> Result<(), LogError> {
+        self.published.borrow_mut().push((topic_arn.to_string(), message.to_string()));
+        Ok(())
+    }
+}
+
+#[test]
+fn check_test() {
+    let notifier = MockNotifier { published: ::std::cell::RefCell::new(Vec::new()) };
+    let config = Config::from_vars(|key| match key {
+        "FAIL_ALERT_THRESHOLD" => Some("0.25".to_string()),
+        "SNS_TOPIC_ARN" => Some("arn:aws:sns:ap-northeast-1:123456789012:alerts".to_string()),
+        _ => None,
+    }).unwrap();
+
+    let quiet = Summary { ok: 3, failed: 1, dropped: 0 };
+    assert!(!check(&config, Some(&notifier), "ap-northeast-1", &quiet).unwrap());
+    assert!(!check(&config, Some(&notifier), "ap-northeast-1", &Summary::default()).unwrap());
+    assert!(notifier.published.borrow().is_empty());
+
+    let failing = Summary { ok: 1, failed: 2, dropped: 1 };
+    assert!(check(&config, Some(&notifier), "ap-northeast-1", &failing).unwrap());
+    let published = notifier.published.borrow();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].0, "arn:aws:sns:ap-northeast-1:123456789012:alerts");
+    let message: Value = serde_json::from_str(&published[0].1).unwrap();
+    assert_eq!(message, serde_json::json!({"region": "ap-northeast-1", "ok": 1, "failed": 2, "dropped": 1, "failure_rate": 0.5}));
+}
+
+#[test]
+fn check_without_topic_test() {
+    let notifier = MockNotifier { published: ::std::cell::RefCell::new(Vec::new()) };
+    let failing = Summary { ok: 0, failed: 1, dropped: 0 };
+
+    assert!(!check(&Config::default(), Some(&notifier), "ap-northeast-1", &failing).unwrap());
+
+    let config = Config::from_vars(|key| match key {
+        "FAIL_ALERT_THRESHOLD" => Some("0".to_string()),
+        _ => None,
+    }).unwrap();
+    assert!(check(&config, Some(&notifier), "ap-northeast-1", &failing).unwrap());
+    assert!(notifier.published.borrow().is_empty());
+}
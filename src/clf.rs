@@ -0,0 +1,199 @@
+//! Hand-written scanner for the built-in Apache pattern, which covers the
+//! Common and Combined Log Formats without running the regex.
+//!
+//! `scan` accepts exactly the lines the pattern matches and returns the same
+//! captures, or `None` for anything it isn't sure of, such as non-ASCII
+//! text, so that the caller falls back to the regex.
+
+/// The groups of the Apache pattern, borrowed from the line.
+#[derive(Debug, PartialEq)]
+pub struct ClfFields<'t> {
+    pub host: &'t str,
+    pub ident: &'t str,
+    pub authuser: &'t str,
+    /// `None` for an empty `[]`.
+    pub time: Option<&'t str>,
+    pub request: &'t str,
+    pub status: &'t str,
+    pub bytes: &'t str,
+    pub referer: Option<&'t str>,
+    pub user_agent: Option<&'t str>,
+}
+
+impl<'t> ClfFields<'t> {
+    /// The capture named `name`, as `Captures::name` would return it.
+    pub fn name(&self, name: &str) -> Option<&'t str> {
+        match name {
+            "host" => Some(self.host),
+            "ident" => Some(self.ident),
+            "authuser" => Some(self.authuser),
+            "time" => self.time,
+            "request" => Some(self.request),
+            "status" => Some(self.status),
+            "bytes" => Some(self.bytes),
+            "referer" => self.referer,
+            "user_agent" => self.user_agent,
+            _ => None,
+        }
+    }
+}
+
+/// `\s`, restricted to ASCII. Unlike `u8::is_ascii_whitespace` this
+/// includes the vertical tab, as the regex does.
+fn is_space(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r')
+}
+
+/// `[\w:/.]`, restricted to ASCII.
+fn is_time(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'_' | b':' | b'/' | b'.')
+}
+
+/// `\S+ `, returning the `\S+` and moving `i` past the space.
+fn token<'t>(line: &'t str, i: &mut usize) -> Option<&'t str> {
+    let b = line.as_bytes();
+    let start = *i;
+    while *i < b.len() && !is_space(b[*i]) {
+        *i += 1;
+    }
+    if *i == start || b.get(*i) != Some(&b' ') {
+        return None;
+    }
+    *i += 1;
+    Some(&line[start..*i - 1])
+}
+
+/// `(?:\s[\+\-]\d{2}:?\d{2})?\]` from `i`, returning the index of the `]`.
+fn offset_end(b: &[u8], i: usize) -> Option<usize> {
+    let digits = |i: usize| b.get(i).is_some_and(u8::is_ascii_digit) && b.get(i + 1).is_some_and(u8::is_ascii_digit);
+    match b.get(i) {
+        Some(&b']') => Some(i),
+        Some(&x) if is_space(x) && matches!(b.get(i + 1), Some(&b'+') | Some(&b'-')) && digits(i + 2) => {
+            let j = if b.get(i + 4) == Some(&b':') { i + 5 } else { i + 4 };
+            if digits(j) && b.get(j + 2) == Some(&b']') { Some(j + 2) } else { None }
+        }
+        _ => None,
+    }
+}
+
+/// `" (?P<status>\d{3}) (?P<bytes>\d+|-)` at `i`, returning both and the
+/// index after `bytes`.
+fn status_bytes(line: &str, i: usize) -> Option<(&str, &str, usize)> {
+    let b = line.as_bytes();
+    if b.get(i) != Some(&b'"') || b.get(i + 1) != Some(&b' ') || b.get(i + 5) != Some(&b' ') {
+        return None;
+    }
+    if !b[i + 2..i + 5].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let start = i + 6;
+    let end = match b.get(start) {
+        Some(&b'-') => start + 1,
+        Some(x) if x.is_ascii_digit() => start + b[start..].iter().take_while(|x| x.is_ascii_digit()).count(),
+        _ => return None,
+    };
+    Some((&line[i + 2..i + 5], &line[start..end], end))
+}
+
+/// `(?: "(?P<referer>[^"]*)" "(?P<user_agent>[^"]*)")?` at `i`.
+fn quoted_pair(line: &str, i: usize) -> Option<(&str, &str)> {
+    let rest = line[i..].strip_prefix(" \"")?;
+    let referer_end = rest.find('"')?;
+    let rest2 = rest[referer_end..].strip_prefix("\" \"")?;
+    let user_agent_end = rest2.find('"')?;
+    Some((&rest[..referer_end], &rest2[..user_agent_end]))
+}
+
+/// Matches `line` as the Apache pattern would, without its
+/// backtracking.
+pub fn scan(line: &str) -> Option<ClfFields<'_>> {
+    if !line.is_ascii() || line.contains('\n') {
+        return None;
+    }
+    let b = line.as_bytes();
+
+    let mut i = 0;
+    let host = token(line, &mut i)?;
+    let ident = token(line, &mut i)?;
+    let authuser = token(line, &mut i)?;
+    if b.get(i) != Some(&b'[') {
+        return None;
+    }
+    i += 1;
+
+    let time_start = i;
+    while i < b.len() && is_time(b[i]) {
+        i += 1;
+    }
+    let time_end = if i == time_start {
+        if b.get(i) != Some(&b']') {
+            return None;
+        }
+        i
+    } else {
+        offset_end(b, i)?
+    };
+    let time = if i == time_start { None } else { Some(&line[time_start..time_end]) };
+    if b.get(time_end + 1) != Some(&b' ') || b.get(time_end + 2) != Some(&b'"') {
+        return None;
+    }
+
+    // `(?P<request>(?:\\.|[^\\\n])+?)"` is lazy: the request ends at the
+    // first quote, after at least one character and not escaped by a
+    // backslash, that the status and bytes follow.
+    let request_start = time_end + 3;
+    let mut j = request_start;
+    let (request_end, status, bytes, end) = loop {
+        match b.get(j) {
+            Some(&b'\\') if j + 1 < b.len() => j += 2,
+            Some(&b'"') if j > request_start => match status_bytes(line, j) {
+                Some((status, bytes, end)) => break (j, status, bytes, end),
+                None => j += 1,
+            },
+            Some(&x) if x != b'\\' => j += 1,
+            _ => return None,
+        }
+    };
+
+    let pair = quoted_pair(line, end);
+    Some(ClfFields {
+        host,
+        ident,
+        authuser,
+        time,
+        request: &line[request_start..request_end],
+        status,
+        bytes,
+        referer: pair.map(|x| x.0),
+        user_agent: pair.map(|x| x.1),
+    })
+}
+
+#[test]
+fn scan_test() {
+    let line = r#"7.248.7.119 - frank [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 "http://example.com/" "curl/7.46.0""#;
+    assert_eq!(scan(line), Some(ClfFields {
+        host: "7.248.7.119",
+        ident: "-",
+        authuser: "frank",
+        time: Some("14/Dec/2017:22:16:45 +09:00"),
+        request: "GET /explore HTTP/1.1",
+        status: "200",
+        bytes: "9947",
+        referer: Some("http://example.com/"),
+        user_agent: Some("curl/7.46.0"),
+    }));
+
+    let fields = scan(r#"::1 - - [] """ 404 - trailing"#).unwrap();
+    assert_eq!(fields.time, None);
+    assert_eq!(fields.request, "\"");
+    assert_eq!(fields.bytes, "-");
+    assert_eq!(fields.referer, None);
+
+    let fields = scan(r#"::1 - - [] "GET /a\" 200 1\\" 200 5"#).unwrap();
+    assert_eq!(fields.request, r#"GET /a\" 200 1\\"#);
+    assert_eq!(fields.bytes, "5");
+
+    assert_eq!(scan("garbage"), None);
+    assert_eq!(scan(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /é" 200 1"#), None);
+}
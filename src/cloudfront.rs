@@ -0,0 +1,79 @@
+//! CloudFront standard logs: W3C extended files with tab-separated rows and
+//! URL-encoded text columns.
+
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value};
+
+use {query_params, RecordError, Transformed};
+use config::Config;
+use w3c;
+
+/// Columns CloudFront percent-encodes, where `+` is a literal plus. The
+/// query is form-encoded instead; it is kept as logged, and its parameters
+/// decoded into `query`.
+static ENCODED_FIELDS: &[&str] = &["cs-uri-stem", "cs(User-Agent)", "cs(Referer)"];
+
+/// Decodes the encoded columns, adds `query`, and drops `date` and `time`,
+/// which `@timestamp` already combines.
+pub fn decode_row(map: &mut Map<String, Value>) {
+    for &field in ENCODED_FIELDS {
+        if let Some(x) = map.get_mut(field) {
+            if let Some(s) = x.as_str().map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned()) {
+                *x = Value::from(s);
+            }
+        }
+    }
+    let query = map.get("cs-uri-query").and_then(|x| x.as_str()).map(query_params).unwrap_or_default();
+    map.insert("query".to_string(), Value::from(query.into_iter().map(|(k, v)| (k, Value::from(v))).collect::<Map<_, _>>()));
+    if map.contains_key("@timestamp") {
+        map.remove("date");
+        map.remove("time");
+    }
+}
+
+/// `transform_data` for CloudFront records.
+pub fn transform(config: &Config, s: &str) -> Result<Option<Transformed>, RecordError> {
+    w3c::transform_rows(config, s, decode_row)
+}
+
+#[cfg(test)]
+static CLOUDFRONT_LOG: &str = "#Version: 1.0
+#Fields: date time x-edge-location sc-bytes c-ip cs-method cs(Host) cs-uri-stem sc-status cs(Referer) cs(User-Agent) cs-uri-query cs(Cookie) x-edge-result-type x-edge-request-id x-host-header cs-protocol cs-bytes time-taken
+2019-12-04\t21:02:31\tLAX1\t392\t192.0.2.100\tGET\td111111abcdef8.cloudfront.net\t/my%20index.html\t200\t-\tMozilla/5.0%20(Windows%20NT%2010.0;%20Win64;%20x64)\tq=rust%20lang&page=1\t-\tHit\tSOX4xwn4XV6Q4rgb7XiVGOHms_BGlTAC4KyHmureZmBNrjGdRLiNIQ==\td111111abcdef8.cloudfront.net\thttps\t23\t0.001
+";
+
+#[test]
+fn transform_test() {
+    let out = transform(&Config::default(), CLOUDFRONT_LOG).unwrap().unwrap();
+    let line: Value = ::serde_json::from_slice(&out.data).unwrap();
+
+    assert_eq!(line["@timestamp"], "2019-12-04T21:02:31+00:00");
+    assert_eq!(line.get("date"), None);
+    assert_eq!(line.get("time"), None);
+    assert_eq!(line["cs-uri-stem"], "/my index.html");
+    assert_eq!(line["cs-uri-query"], "q=rust%20lang&page=1");
+    assert_eq!(line["query"], ::serde_json::json!({"q": "rust lang", "page": "1"}));
+    assert_eq!(line["cs(User-Agent)"], "Mozilla/5.0 (Windows NT 10.0; Win64; x64)");
+    assert_eq!(line["cs(Referer)"], Value::Null);
+    assert_eq!(line["sc-status"], 200);
+    assert_eq!(line["time-taken"], 0.001);
+    assert_eq!(out.partition_keys["day"], "04");
+}
+
+#[test]
+fn decode_row_query_test() {
+    let mut map = Map::new();
+    map.insert("cs-uri-query".to_string(), Value::from("q=a%26b%3Dc&tag=x+y"));
+    decode_row(&mut map);
+    assert_eq!(map["cs-uri-query"], "q=a%26b%3Dc&tag=x+y");
+    assert_eq!(map["query"], ::serde_json::json!({"q": "a&b=c", "tag": "x y"}));
+}
+
+#[test]
+fn transform_drop_path_test() {
+    // Matched against the decoded `cs-uri-stem`.
+    let config = Config::from_vars(|key| if key == "DROP_PATH_REGEX" { Some(r"^/my index\.html$".to_string()) } else { None }).unwrap();
+    assert!(transform(&config, CLOUDFRONT_LOG).unwrap().is_none());
+    let config = Config::from_vars(|key| if key == "DROP_PATH_REGEX" { Some("rust".to_string()) } else { None }).unwrap();
+    assert!(transform(&config, CLOUDFRONT_LOG).unwrap().is_some());
+}
@@ -0,0 +1,1040 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::str::FromStr;
+#[cfg(feature = "parallel")]
+use std::sync::OnceLock;
+use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono_tz::Tz;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use regex::{Regex, RegexSet};
+use serde_json;
+
+use {status_class, LogError};
+use geoip::GeoDb;
+use util::Semaphore;
+use flavor::{Fields, LineFormat, LogFlavor};
+use logformat::{compile_separated_format, TSV_FORMATS};
+use rules::{self, Rule};
+
+/// Runtime settings, read once from the Lambda environment at startup.
+pub struct Config {
+    /// Formats tried against each log line, with named groups as produced
+    /// by `compile_format`. Taken from `LOG_FLAVOR`, where `auto` selects
+    /// every known flavor, or else from `LOG_PATTERN` as a raw regex or
+    /// `LOG_FORMAT` as an Apache `LogFormat` string. `FIELD_SEPARATOR=tab`
+    /// reads `LOG_FORMAT`, or else `TSV_FORMATS`, as tab-separated.
+    /// `TIMESTAMP_FORMATS` replaces the time formats of each. `UNQUOTED_TAIL`
+    /// takes Apache's referer and user-agent by position, for configs that
+    /// log them without quotes.
+    pub formats: Vec<LineFormat>,
+    /// Index into `formats` of the last one that matched, tried first.
+    last_format: AtomicUsize,
+    /// How lines are turned into JSON. `LOG_FLAVOR` of `w3c`, `cloudfront`,
+    /// `alb` or `apache_error` selects a parser of its own, which ignores
+    /// `formats`.
+    pub parser: Parser,
+    /// Output field renames, keyed by the default field name. Parsed from
+    /// `FIELD_MAP` as a JSON object, plus `TS_FIELD` and `TS_UTC_FIELD` as
+    /// shorthands for renaming `@timestamp` and `@timestamp_utc`.
+    pub field_map: BTreeMap<String, String>,
+    /// Edits made to each output object before `exclude_fields` and
+    /// `field_map`. Parsed from `TRANSFORM_RULES` by `rules::parse`.
+    pub transform_rules: Vec<Rule>,
+    /// Output fields removed before `field_map` applies, by their default
+    /// names. Set by `EXCLUDE_FIELDS` as a comma-separated list.
+    pub exclude_fields: Vec<String>,
+    /// Field names access log lines are written with. Set by
+    /// `OUTPUT_SCHEMA`.
+    pub output_schema: OutputSchema,
+    /// How output lines are written. Set by `OUTPUT_FORMAT`.
+    pub output_format: OutputFormat,
+    /// Start each record's CSV output with a header row. Set by
+    /// `CSV_HEADER`.
+    pub csv_header: bool,
+    /// Object each output line is wrapped in, from the JSON template in
+    /// `OUTPUT_ENVELOPE`. Unset writes lines bare.
+    pub output_envelope: Option<Envelope>,
+    /// How the `-` Apache logs for an unknown ident, authuser, referer,
+    /// user-agent or header is written. Set by `DASH_HANDLING`.
+    pub dash_handling: DashHandling,
+    /// City database used to attach `geo` to each line. Loaded from the
+    /// path in `GEOIP_DB`.
+    pub geoip: Option<GeoDb>,
+    /// Mask client addresses with `geoip::anonymize` before they are looked
+    /// up or written out. Set by `ANONYMIZE_IP`. The `raw` line kept by
+    /// `RETAIN_RAW` is not masked.
+    pub anonymize_ip: bool,
+    /// Drop lines whose user-agent is classified as a crawler, or for
+    /// access logs as a bot. Set by `DROP_CRAWLERS`.
+    pub drop_crawlers: bool,
+    /// Drop lines whose path matches: `url_path` for access logs, the
+    /// request URL's path for ALB and `cs-uri-stem` for W3C and CloudFront.
+    /// Set by `DROP_PATH_REGEX`, e.g. `\.(css|js|png)$` for static assets.
+    pub drop_path_regex: Option<Regex>,
+    /// User-agents taken for bots besides woothee's crawlers. Set by
+    /// `BOT_UA_PATTERNS` as a JSON array of regexes.
+    pub bot_ua_patterns: Option<RegexSet>,
+    /// How `@timestamp` and `@timestamp_utc` are written. Set by
+    /// `TIMESTAMP_FORMAT`.
+    pub timestamp_format: TimestampFormat,
+    /// Which of `@timestamp` and `@timestamp_utc` are written. Set by
+    /// `TIMESTAMP_MODE`.
+    pub timestamp_mode: TimestampMode,
+    /// Zone used to interpret timestamps logged without an offset. Set by
+    /// `DEFAULT_TZ` as an IANA name such as `Asia/Tokyo`.
+    pub default_tz: Option<Tz>,
+    /// CloudWatch namespace for the EMF metrics. Set by `METRIC_NAMESPACE`.
+    pub metric_namespace: String,
+    /// Attach `metadata.partitionKeys` for Firehose dynamic partitioning.
+    /// Set by `PARTITION_KEYS`.
+    pub partition_keys: bool,
+    /// Largest base64 output a Firehose record may have before it is failed
+    /// instead. Set by `MAX_RECORD_BYTES`; defaults to Firehose's 6MiB.
+    pub max_record_bytes: usize,
+    /// Longest line parsed; longer ones fail without being matched. Set by
+    /// `MAX_LINE_BYTES`; defaults to 64KiB.
+    pub max_line_bytes: usize,
+    /// Add the line as received as `raw` to each output object. Set by
+    /// `RETAIN_RAW`.
+    pub retain_raw: bool,
+    /// Deliver Firehose records that fail to transform unchanged as `Ok`
+    /// instead of `ProcessingFailed`. Set by `PASSTHROUGH_ON_PARSE_ERROR`.
+    pub passthrough_on_parse_error: bool,
+    /// Let optional fields that fail to parse, such as `bytes`, go out as
+    /// `null` and be listed in `_parse_warnings`, instead of failing the
+    /// line. Set by `LENIENT`.
+    pub lenient: bool,
+    /// Add each Firehose record's arrival time as `firehose_arrival_time`
+    /// to its output lines. Set by `INCLUDE_ARRIVAL_TS`.
+    pub include_arrival_ts: bool,
+    /// Add the Firehose invocation's ID as `invocation_id` to every output
+    /// line, so that lines from a retried invocation can be told apart
+    /// downstream. Set by `INCLUDE_INVOCATION_ID`.
+    pub include_invocation_id: bool,
+    /// Name of the stream or function the output came from, added as
+    /// `source_stream` to every output line so that aggregated streams can
+    /// be told apart. Set by `RECORD_ID_PREFIX`; the `record_id` itself is
+    /// left alone, as Firehose matches the response to the input by it.
+    pub source_stream: Option<String>,
+    /// Have the S3 and Kinesis handlers write each output line as an item
+    /// of its own: an object per line, or a Kinesis result per line. Set by
+    /// `FANOUT`; Firehose can't fan out and ignores it.
+    pub fanout: bool,
+    /// Have `my_handler` transform each invocation for its metrics and logs
+    /// but answer with every record as it came in, `Ok`, so that a canary
+    /// leaves the stream alone. Set by `DRY_RUN`.
+    pub dry_run: bool,
+    /// Whether Snappy payloads are framed, and found by their stream
+    /// identifier, or raw. Set by `SNAPPY_FRAMING`; raw needs the
+    /// `compression` feature.
+    pub snappy_framing: SnappyFraming,
+    /// How payloads are decoded to text. Set by `TEXT_ENCODING`.
+    pub text_encoding: TextEncoding,
+    /// Status classes such as `4xx` whose lines are kept; others are
+    /// dropped. Set by `STATUS_FILTER` as a comma-separated list, where
+    /// `all`, the default, keeps every line.
+    pub status_filter: Option<Vec<String>>,
+    /// Fraction of Firehose records kept, chosen by record ID; the rest are
+    /// `Dropped`. Set by `SAMPLE_RATE` between `0` and `1`, the default.
+    pub sample_rate: f64,
+    /// Failed share of an invocation's records over which an alert is
+    /// raised. Set by `FAIL_ALERT_THRESHOLD` between `0` and `1`; unset
+    /// never alerts.
+    pub fail_alert_threshold: Option<f64>,
+    /// Topic alerts are published to. Set by `SNS_TOPIC_ARN`; unset only
+    /// logs them.
+    pub sns_topic_arn: Option<String>,
+    /// Number of threads records are transformed on. Set by `PARALLELISM`,
+    /// or else `RAYON_THREADS`; `1` skips Rayon entirely, and unset uses one
+    /// thread per vCPU. Without the `parallel` feature every batch is
+    /// sequential and only `1` is accepted.
+    pub parallelism: Option<usize>,
+    /// Bounds the lookups made by `enrich` across threads. Set by
+    /// `ENRICH_CONCURRENCY`, or else `threads()`.
+    enrich_limit: Semaphore,
+    /// Pool of `threads()` threads, built once, by `init_pool` or the first
+    /// batch that needs it. `None` inside if Rayon couldn't start it, in
+    /// which case batches run sequentially.
+    #[cfg(feature = "parallel")]
+    pool: OnceLock<Option<ThreadPool>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parser {
+    /// Regex formats matched one line at a time into an `AccessLog`.
+    Access,
+    /// W3C extended log files, whose columns are set by `#Fields:`.
+    W3c,
+    /// Application Load Balancer access logs, into an `AlbLog`.
+    Alb,
+    /// CloudFront standard logs, W3C with URL-encoded columns.
+    CloudFront,
+    /// Apache error logs, into an `ErrorLog`.
+    ApacheError,
+}
+
+impl FromStr for Parser {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<Parser, LogError> {
+        match s {
+            "w3c" => Ok(Parser::W3c),
+            "alb" => Ok(Parser::Alb),
+            "cloudfront" => Ok(Parser::CloudFront),
+            "apache_error" => Ok(Parser::ApacheError),
+            _ => Err(LogError::FormatError(format!("unknown parser {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampFormat {
+    /// RFC 3339 strings, keeping the logged offset in `@timestamp`.
+    Rfc3339,
+    /// Milliseconds since the Unix epoch, as a JSON number.
+    EpochMillis,
+    /// Seconds since the Unix epoch, as a JSON number.
+    EpochSeconds,
+}
+
+/// What separates the fields of a `LOG_FORMAT` line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldSeparator {
+    /// Single spaces, as in the Common and Combined Log Formats.
+    Space,
+    /// Tabs, for TSV logs.
+    Tab,
+}
+
+impl FromStr for FieldSeparator {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<FieldSeparator, LogError> {
+        match s.to_lowercase().as_str() {
+            "space" | " " => Ok(FieldSeparator::Space),
+            "tab" | "\t" => Ok(FieldSeparator::Tab),
+            _ => Err(LogError::FormatError(format!("unknown field separator {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DashHandling {
+    /// `null`.
+    Null,
+    /// An empty string.
+    Empty,
+    /// The `-` as logged.
+    Literal,
+}
+
+impl FromStr for DashHandling {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<DashHandling, LogError> {
+        match s.to_lowercase().as_str() {
+            "null" => Ok(DashHandling::Null),
+            "empty" => Ok(DashHandling::Empty),
+            "literal" => Ok(DashHandling::Literal),
+            _ => Err(LogError::FormatError(format!("unknown dash handling {}", s))),
+        }
+    }
+}
+
+/// Which timestamps go out. The choice is made on the default field names,
+/// before `FIELD_MAP`, `TS_FIELD` and `TS_UTC_FIELD` rename what is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampMode {
+    /// `@timestamp` and `@timestamp_utc`.
+    Both,
+    /// `@timestamp` alone, in the logged offset.
+    LocalOnly,
+    /// `@timestamp_utc` alone. Output without one, such as ECS, keeps its
+    /// `@timestamp`.
+    UtcOnly,
+}
+
+impl FromStr for TimestampMode {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<TimestampMode, LogError> {
+        match s.to_lowercase().as_str() {
+            "both" => Ok(TimestampMode::Both),
+            "local_only" => Ok(TimestampMode::LocalOnly),
+            "utc_only" => Ok(TimestampMode::UtcOnly),
+            _ => Err(LogError::FormatError(format!("unknown timestamp mode {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnappyFraming {
+    /// The framing format, opened by a stream identifier.
+    Framed,
+    /// Bare Snappy blocks, tried on every payload without another magic.
+    Raw,
+}
+
+impl FromStr for SnappyFraming {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<SnappyFraming, LogError> {
+        match s.to_lowercase().as_str() {
+            "framed" => Ok(SnappyFraming::Framed),
+            "raw" if cfg!(feature = "compression") => Ok(SnappyFraming::Raw),
+            "raw" => Err(LogError::FormatError("raw Snappy needs the compression feature".to_string())),
+            _ => Err(LogError::FormatError(format!("unknown Snappy framing {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// One JSON object per line.
+    Json,
+    /// One CSV row per line, in the columns of `CSV_COLUMNS`. Ignores
+    /// `OUTPUT_ENVELOPE`.
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<OutputFormat, LogError> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(LogError::FormatError(format!("unknown output format {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputSchema {
+    /// The fields of `AccessLog`, as they are named there.
+    Flat,
+    /// Elastic Common Schema, nested as `ecs::access_log` builds it.
+    Ecs,
+}
+
+impl FromStr for OutputSchema {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<OutputSchema, LogError> {
+        match s.to_lowercase().as_str() {
+            "flat" => Ok(OutputSchema::Flat),
+            "ecs" => Ok(OutputSchema::Ecs),
+            _ => Err(LogError::FormatError(format!("unknown output schema {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    /// UTF-8, failing the record on any invalid byte.
+    Utf8,
+    /// UTF-8, or else Latin-1.
+    Latin1,
+    /// UTF-8, with invalid bytes replaced by U+FFFD.
+    Lossy,
+}
+
+impl FromStr for TextEncoding {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<TextEncoding, LogError> {
+        match s.to_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(TextEncoding::Utf8),
+            "latin1" | "latin-1" => Ok(TextEncoding::Latin1),
+            "lossy" => Ok(TextEncoding::Lossy),
+            _ => Err(LogError::FormatError(format!("unknown text encoding {}", s))),
+        }
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<TimestampFormat, LogError> {
+        match s.to_lowercase().as_str() {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch_millis" => Ok(TimestampFormat::EpochMillis),
+            "epoch_seconds" => Ok(TimestampFormat::EpochSeconds),
+            _ => Err(LogError::FormatError(format!("unknown timestamp format {}", s))),
+        }
+    }
+}
+
+/// The text around the `"{{event}}"` placeholder of an `OUTPUT_ENVELOPE`
+/// template, serialized once so that each line is written between them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    prefix: String,
+    suffix: String,
+}
+
+static EVENT_PLACEHOLDER: &str = "\"{{event}}\"";
+
+impl Envelope {
+    /// Writes `value` wrapped in the envelope.
+    pub fn write(&self, out: &mut Vec<u8>, value: &serde_json::Value) -> Result<(), LogError> {
+        out.extend_from_slice(self.prefix.as_bytes());
+        serde_json::to_writer(&mut *out, value)?;
+        out.extend_from_slice(self.suffix.as_bytes());
+        Ok(())
+    }
+}
+
+impl FromStr for Envelope {
+    type Err = LogError;
+
+    /// Parses a JSON template holding `"{{event}}"` exactly once, as a value.
+    fn from_str(s: &str) -> Result<Envelope, LogError> {
+        let template = serde_json::to_string(&serde_json::from_str::<serde_json::Value>(s)?)?;
+        let mut parts = template.split(EVENT_PLACEHOLDER);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(prefix), Some(suffix), None) if !suffix.starts_with(':') => {
+                Ok(Envelope { prefix: prefix.to_string(), suffix: suffix.to_string() })
+            }
+            _ => Err(LogError::FormatError(format!("OUTPUT_ENVELOPE needs one {} value", EVENT_PLACEHOLDER))),
+        }
+    }
+}
+
+#[test]
+fn envelope_test() {
+    let envelope: Envelope = r#"{"source": "apache", "event": "{{event}}"}"#.parse().unwrap();
+    let mut out = Vec::new();
+    envelope.write(&mut out, &serde_json::json!({"host": "7.248.7.119"})).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), r#"{"event":{"host":"7.248.7.119"},"source":"apache"}"#);
+
+    assert_eq!(r#"["{{event}}"]"#.parse::<Envelope>().unwrap().prefix, "[");
+    assert!(r#"{"event": {{event}}}"#.parse::<Envelope>().is_err());
+    assert!(r#"{"source": "apache"}"#.parse::<Envelope>().is_err());
+    assert!(r#"{"a": "{{event}}", "b": "{{event}}"}"#.parse::<Envelope>().is_err());
+    assert!(r#"{"{{event}}": 1}"#.parse::<Envelope>().is_err());
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, LogError> {
+        Config::from_vars(|key| env::var(key).ok())
+    }
+
+    /// Builds a `Config` from an arbitrary variable lookup, falling back to
+    /// the defaults for anything that is unset.
+    pub fn from_vars<F>(var: F) -> Result<Config, LogError>
+        where F: Fn(&str) -> Option<String>
+    {
+        let mut config = Config::default();
+
+        let flavors = match var("LOG_FLAVOR") {
+            Some(ref x) if x == "auto" => LogFlavor::all().to_vec(),
+            Some(ref x) if ["w3c", "alb", "cloudfront", "apache_error"].contains(&x.as_str()) => {
+                config.parser = x.parse()?;
+                vec![LogFlavor::Apache]
+            }
+            Some(x) => vec![x.parse()?],
+            None => vec![LogFlavor::Apache],
+        };
+
+        let separator = match var("FIELD_SEPARATOR") {
+            Some(x) => x.parse()?,
+            None => FieldSeparator::Space,
+        };
+        let unquoted_tail = flag(var("UNQUOTED_TAIL"));
+        if unquoted_tail && (var("LOG_PATTERN").is_some() || var("LOG_FORMAT").is_some() || separator == FieldSeparator::Tab) {
+            return Err(LogError::FormatError("UNQUOTED_TAIL only applies to the built-in space-separated formats".to_string()));
+        }
+        if let Some(pattern) = var("LOG_PATTERN") {
+            config.formats = vec![LineFormat::new(Regex::new(&pattern)?, flavors[0])];
+        } else if let Some(format) = var("LOG_FORMAT") {
+            config.formats = vec![compiled_line_format(&format, separator, flavors[0])?];
+        } else if separator == FieldSeparator::Tab {
+            config.formats = TSV_FORMATS.iter()
+                .map(|x| compiled_line_format(x, separator, LogFlavor::Apache))
+                .collect::<Result<_, _>>()?;
+        } else if unquoted_tail {
+            config.formats = flavors.into_iter()
+                .map(|x| Ok(LineFormat::new(Regex::new(x.unquoted_tail_pattern())?, x)))
+                .collect::<Result<_, LogError>>()?;
+        } else {
+            config.formats = flavors.into_iter().map(LineFormat::from).collect();
+        }
+        if let Some(time_formats) = var("TIMESTAMP_FORMATS") {
+            let time_formats = parse_time_formats(&time_formats)?;
+            for format in &mut config.formats {
+                format.time_formats = time_formats.clone();
+            }
+        }
+
+        if let Some(field_map) = var("FIELD_MAP") {
+            config.field_map = serde_json::from_str(&field_map)?;
+        }
+        for &(key, field) in &[("TS_FIELD", "@timestamp"), ("TS_UTC_FIELD", "@timestamp_utc")] {
+            if let Some(name) = var(key) {
+                config.field_map.entry(field.to_string()).or_insert(name);
+            }
+        }
+
+        if let Some(rules) = var("TRANSFORM_RULES") {
+            config.transform_rules = rules::parse(&rules)?;
+        }
+
+        if let Some(fields) = var("EXCLUDE_FIELDS") {
+            config.exclude_fields = fields.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+        }
+
+        if let Some(format) = var("OUTPUT_FORMAT") {
+            config.output_format = format.parse()?;
+        }
+        config.csv_header = flag(var("CSV_HEADER"));
+
+        if let Some(schema) = var("OUTPUT_SCHEMA") {
+            config.output_schema = schema.parse()?;
+        }
+
+        if let Some(template) = var("OUTPUT_ENVELOPE") {
+            config.output_envelope = Some(template.parse()?);
+        }
+
+        if let Some(handling) = var("DASH_HANDLING") {
+            config.dash_handling = handling.parse()?;
+        }
+
+        if let Some(path) = var("GEOIP_DB") {
+            config.geoip = Some(GeoDb::open(&path)?);
+        }
+
+        config.drop_crawlers = flag(var("DROP_CRAWLERS"));
+        if let Some(pattern) = var("DROP_PATH_REGEX") {
+            config.drop_path_regex = Some(Regex::new(&pattern)?);
+        }
+        if let Some(patterns) = var("BOT_UA_PATTERNS") {
+            config.bot_ua_patterns = Some(RegexSet::new(serde_json::from_str::<Vec<String>>(&patterns)?)?);
+        }
+        config.anonymize_ip = flag(var("ANONYMIZE_IP"));
+        config.partition_keys = flag(var("PARTITION_KEYS"));
+        config.passthrough_on_parse_error = flag(var("PASSTHROUGH_ON_PARSE_ERROR"));
+        config.lenient = flag(var("LENIENT"));
+        config.include_arrival_ts = flag(var("INCLUDE_ARRIVAL_TS"));
+        config.include_invocation_id = flag(var("INCLUDE_INVOCATION_ID"));
+        config.fanout = flag(var("FANOUT"));
+        config.dry_run = flag(var("DRY_RUN"));
+        config.source_stream = var("RECORD_ID_PREFIX").filter(|x| !x.is_empty());
+        config.retain_raw = flag(var("RETAIN_RAW"));
+        if config.output_format == OutputFormat::Csv {
+            let fields = [
+                ("RECORD_ID_PREFIX", config.source_stream.is_some()),
+                ("INCLUDE_INVOCATION_ID", config.include_invocation_id),
+                ("INCLUDE_ARRIVAL_TS", config.include_arrival_ts),
+            ];
+            if let Some(&(key, _)) = fields.iter().find(|x| x.1) {
+                return Err(LogError::FormatError(format!("OUTPUT_FORMAT=csv has no column for the field {} adds", key)));
+            }
+        }
+
+        if let Some(format) = var("TIMESTAMP_FORMAT") {
+            config.timestamp_format = format.parse()?;
+        }
+        if let Some(mode) = var("TIMESTAMP_MODE") {
+            config.timestamp_mode = mode.parse()?;
+        }
+
+        if let Some(encoding) = var("TEXT_ENCODING") {
+            config.text_encoding = encoding.parse()?;
+        }
+
+        if let Some(framing) = var("SNAPPY_FRAMING") {
+            config.snappy_framing = framing.parse()?;
+        }
+
+        if let Some(tz) = var("DEFAULT_TZ") {
+            let tz = tz.parse().map_err(|_| LogError::FormatError(format!("unknown time zone {}", tz)))?;
+            config.default_tz = Some(tz);
+        }
+
+        if let Some(namespace) = var("METRIC_NAMESPACE") {
+            config.metric_namespace = namespace;
+        }
+
+        if let Some(n) = var("MAX_RECORD_BYTES") {
+            config.max_record_bytes = n.parse()?;
+        }
+
+        if let Some(n) = var("MAX_LINE_BYTES") {
+            config.max_line_bytes = n.parse()?;
+        }
+
+        if let Some(filter) = var("STATUS_FILTER") {
+            config.status_filter = parse_status_filter(&filter)?;
+        }
+
+        if let Some(rate) = var("SAMPLE_RATE") {
+            let rate = rate.parse::<f64>()?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(LogError::FormatError("SAMPLE_RATE must be between 0 and 1".to_string()));
+            }
+            config.sample_rate = rate;
+        }
+
+        if let Some(threshold) = var("FAIL_ALERT_THRESHOLD") {
+            let threshold = threshold.parse::<f64>()?;
+            if !(0.0..=1.0).contains(&threshold) {
+                return Err(LogError::FormatError("FAIL_ALERT_THRESHOLD must be between 0 and 1".to_string()));
+            }
+            config.fail_alert_threshold = Some(threshold);
+        }
+        config.sns_topic_arn = var("SNS_TOPIC_ARN");
+
+        let threads = match var("PARALLELISM") {
+            Some(n) => Some(("PARALLELISM", n)),
+            None => var("RAYON_THREADS").map(|n| ("RAYON_THREADS", n)),
+        };
+        if let Some((key, n)) = threads {
+            let n = n.parse::<usize>()?;
+            if n == 0 {
+                return Err(LogError::FormatError(format!("{} must be at least 1", key)));
+            }
+            if n > 1 {
+                config.build_pool(key, n)?;
+            }
+            config.parallelism = Some(n);
+        }
+
+        let enrich_concurrency = match var("ENRICH_CONCURRENCY") {
+            Some(n) => n.parse::<usize>()?,
+            None => config.threads(),
+        };
+        if enrich_concurrency == 0 {
+            return Err(LogError::FormatError("ENRICH_CONCURRENCY must be at least 1".to_string()));
+        }
+        config.enrich_limit = Semaphore::new(enrich_concurrency);
+
+        Ok(config)
+    }
+
+    /// Builds the pool up front, so that an explicitly sized one fails at
+    /// startup rather than silently running sequentially.
+    #[cfg(feature = "parallel")]
+    fn build_pool(&mut self, _: &str, n: usize) -> Result<(), LogError> {
+        let pool = ThreadPoolBuilder::new().num_threads(n).build()
+            .map_err(|e| LogError::FormatError(e.to_string()))?;
+        self.pool = OnceLock::from(Some(pool));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn build_pool(&mut self, key: &str, _: usize) -> Result<(), LogError> {
+        Err(LogError::FormatError(format!("{} above 1 needs the parallel feature", key)))
+    }
+
+    /// Threads each batch is spread over: `parallelism`, or one per vCPU.
+    pub fn threads(&self) -> usize {
+        self.parallelism.unwrap_or_else(detected_threads)
+    }
+
+    /// Starts the pool, if batches need one, so that a Lambda builds it at
+    /// cold start and reuses it for every invocation. Returns the threads
+    /// batches will actually run on.
+    #[cfg(feature = "parallel")]
+    pub fn init_pool(&self) -> usize {
+        match self.pool() {
+            Some(pool) => pool.current_num_threads(),
+            None => 1,
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    pub fn init_pool(&self) -> usize {
+        1
+    }
+
+    #[cfg(feature = "parallel")]
+    fn pool(&self) -> Option<&ThreadPool> {
+        let threads = self.threads();
+        if threads == 1 {
+            return None;
+        }
+        self.pool.get_or_init(|| ThreadPoolBuilder::new().num_threads(threads).build().ok()).as_ref()
+    }
+
+    /// Maps `f` over `items` sequentially or on Rayon, as set by
+    /// `PARALLELISM`, keeping the input order. The result is allocated
+    /// once at `items.len()`; an empty batch never reaches Rayon.
+    #[cfg(feature = "parallel")]
+    pub fn map_records<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+        where T: Sync, R: Send, F: Fn(&T) -> R + Sync + Send
+    {
+        if items.is_empty() {
+            return Vec::new();
+        }
+
+        let mut out = Vec::with_capacity(items.len());
+        match self.pool() {
+            Some(pool) => pool.install(|| items.par_iter().map(f).collect_into_vec(&mut out)),
+            None => out.extend(items.iter().map(f)),
+        }
+        out
+    }
+
+    /// Maps `f` over `items` in order on the calling thread.
+    #[cfg(not(feature = "parallel"))]
+    pub fn map_records<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+        where T: Sync, R: Send, F: Fn(&T) -> R + Sync + Send
+    {
+        items.iter().map(f).collect()
+    }
+
+    /// Runs an enrichment lookup, such as `geoip`, once one of the
+    /// `ENRICH_CONCURRENCY` permits is free.
+    pub fn enrich<T, F: FnOnce() -> T>(&self, lookup: F) -> T {
+        let _permit = self.enrich_limit.acquire();
+        lookup()
+    }
+
+    /// The name `field` is written under, after `FIELD_MAP`.
+    pub fn output_name<'a>(&'a self, field: &'a str) -> &'a str {
+        self.field_map.get(field).map(|x| x.as_str()).unwrap_or(field)
+    }
+
+    /// Whether `user_agent` matches any of `BOT_UA_PATTERNS`.
+    pub fn is_bot_ua(&self, user_agent: &str) -> bool {
+        self.bot_ua_patterns.as_ref().is_some_and(|x| x.is_match(user_agent))
+    }
+
+    /// A text field as written under `DASH_HANDLING`, where `None` is
+    /// `null`.
+    pub fn dash<'a>(&self, s: &'a str) -> Option<&'a str> {
+        match self.dash_handling {
+            _ if s != "-" => Some(s),
+            DashHandling::Null => None,
+            DashHandling::Empty => Some(""),
+            DashHandling::Literal => Some(s),
+        }
+    }
+
+    /// Whether lines with `status` pass `STATUS_FILTER`.
+    pub fn keeps_status(&self, status: u16) -> bool {
+        match self.status_filter {
+            Some(ref classes) => classes.iter().any(|x| x == status_class(status)),
+            None => true,
+        }
+    }
+
+    /// Matches `line` against each of `formats`, starting with the one that
+    /// matched last time.
+    pub fn captures<'t>(&self, line: &'t str) -> Option<(&LineFormat, Fields<'t>)> {
+        let last = self.last_format.load(Ordering::Relaxed);
+        let order = Some(last).into_iter().chain((0..self.formats.len()).filter(|&i| i != last));
+
+        for i in order {
+            if let Some(xs) = self.formats.get(i).and_then(|x| x.captures(line)) {
+                if i != last {
+                    self.last_format.store(i, Ordering::Relaxed);
+                }
+                return Some((&self.formats[i], xs));
+            }
+        }
+        None
+    }
+
+    /// Forgets which format matched last, and which time format parsed
+    /// last, e.g. at the start of an invocation.
+    pub fn reset_last_format(&self) {
+        self.last_format.store(0, Ordering::Relaxed);
+        for format in &self.formats {
+            format.reset_last_time_format();
+        }
+    }
+}
+
+/// `threads()` when `PARALLELISM` is unset.
+fn detected_threads() -> usize {
+    if cfg!(feature = "parallel") {
+        thread::available_parallelism().map(|x| x.get()).unwrap_or(1)
+    } else {
+        1
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            formats: vec![LineFormat::from(LogFlavor::Apache)],
+            last_format: AtomicUsize::new(0),
+            parser: Parser::Access,
+            field_map: BTreeMap::new(),
+            transform_rules: Vec::new(),
+            exclude_fields: Vec::new(),
+            output_schema: OutputSchema::Flat,
+            output_format: OutputFormat::Json,
+            csv_header: false,
+            output_envelope: None,
+            geoip: None,
+            anonymize_ip: false,
+            drop_crawlers: false,
+            drop_path_regex: None,
+            bot_ua_patterns: None,
+            dash_handling: DashHandling::Null,
+            timestamp_format: TimestampFormat::Rfc3339,
+            timestamp_mode: TimestampMode::Both,
+            default_tz: None,
+            metric_namespace: "FirehoseTransform".to_string(),
+            partition_keys: false,
+            passthrough_on_parse_error: false,
+            lenient: false,
+            include_arrival_ts: false,
+            include_invocation_id: false,
+            fanout: false,
+            dry_run: false,
+            source_stream: None,
+            snappy_framing: SnappyFraming::Framed,
+            text_encoding: TextEncoding::Utf8,
+            max_record_bytes: 6 * 1024 * 1024,
+            max_line_bytes: 64 * 1024,
+            retain_raw: false,
+            status_filter: None,
+            sample_rate: 1.0,
+            fail_alert_threshold: None,
+            sns_topic_arn: None,
+            parallelism: None,
+            enrich_limit: Semaphore::new(detected_threads()),
+            #[cfg(feature = "parallel")]
+            pool: OnceLock::new(),
+        }
+    }
+}
+
+/// A `LogFormat` string as a `LineFormat`, parsing time with its
+/// `%{format}t` layout when it has one.
+fn compiled_line_format(format: &str, separator: FieldSeparator, flavor: LogFlavor) -> Result<LineFormat, LogError> {
+    let compiled = compile_separated_format(format, separator)?;
+    let mut line_format = LineFormat::new(compiled.pattern, flavor);
+    if let Some(time_format) = compiled.time_format {
+        line_format.time_formats = vec![time_format];
+    }
+    Ok(line_format)
+}
+
+/// `TIMESTAMP_FORMATS`: `chrono` formats, one per line or separated by
+/// `;`, in the order they are tried.
+fn parse_time_formats(s: &str) -> Result<Vec<String>, LogError> {
+    let formats: Vec<String> = s.split(['\n', ';']).map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect();
+    if formats.is_empty() {
+        return Err(LogError::FormatError("TIMESTAMP_FORMATS has no format".to_string()));
+    }
+    Ok(formats)
+}
+
+#[test]
+fn parse_time_formats_test() {
+    assert_eq!(parse_time_formats("%d/%b/%Y:%H:%M:%S %z;\n %Y-%m-%d %H:%M:%S%.f ; ").unwrap(), vec!["%d/%b/%Y:%H:%M:%S %z", "%Y-%m-%d %H:%M:%S%.f"]);
+    assert!(parse_time_formats(" ; \n").is_err());
+}
+
+fn parse_status_filter(s: &str) -> Result<Option<Vec<String>>, LogError> {
+    let classes: Vec<String> = s.split(',').map(|x| x.trim().to_lowercase()).collect();
+    if classes.iter().any(|x| x == "all") {
+        return Ok(None);
+    }
+    match classes.iter().find(|x| !["1xx", "2xx", "3xx", "4xx", "5xx"].contains(&x.as_str())) {
+        Some(x) => Err(LogError::FormatError(format!("unknown status class {}", x))),
+        None => Ok(Some(classes)),
+    }
+}
+
+#[test]
+fn parse_status_filter_test() {
+    assert_eq!(parse_status_filter("all").unwrap(), None);
+    assert_eq!(parse_status_filter("4xx, 5XX").unwrap(), Some(vec!["4xx".to_string(), "5xx".to_string()]));
+    assert!(parse_status_filter("404").is_err());
+}
+
+/// Boolean environment flags are on for `1`, `true` or `yes`.
+fn flag(value: Option<String>) -> bool {
+    match value {
+        Some(x) => ["1", "true", "yes"].contains(&x.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+#[test]
+fn from_vars_default_test() {
+    let config = Config::from_vars(|_| None).unwrap();
+    assert_eq!(config.formats.len(), 1);
+    assert_eq!(config.formats[0].pattern.as_str(), LogFlavor::Apache.pattern());
+}
+
+#[test]
+fn from_vars_parser_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("w3c".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(config.parser, Parser::W3c);
+
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("alb".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(config.parser, Parser::Alb);
+    assert_eq!("cloudfront".parse::<Parser>().unwrap(), Parser::CloudFront);
+    assert_eq!("apache_error".parse::<Parser>().unwrap(), Parser::ApacheError);
+    assert_eq!(Config::default().parser, Parser::Access);
+}
+
+#[test]
+fn from_vars_flavor_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("nginx".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(config.formats.len(), 1);
+    assert_eq!(config.formats[0].pattern.as_str(), LogFlavor::Nginx.pattern());
+}
+
+#[test]
+fn captures_auto_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("auto".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(config.formats.len(), LogFlavor::all().len());
+
+    // nginx logs an empty request line for malformed requests, which the
+    // Apache pattern rejects.
+    let nginx = r#"2001:db8::1 - - [10/Oct/2000:13:55:36 -0700] "" 400 0 "-" "-""#;
+    let (format, _) = config.captures(nginx).unwrap();
+    assert_eq!(format.pattern.as_str(), LogFlavor::Nginx.pattern());
+    assert_eq!(config.last_format.load(Ordering::Relaxed), 1);
+
+    assert!(config.captures("garbage").is_none());
+    assert_eq!(config.last_format.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn from_vars_invalid_pattern_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_PATTERN" => Some("([".to_string()),
+        _ => None,
+    });
+    assert!(config.is_err());
+}
+
+#[test]
+fn from_vars_csv_record_fields_test() {
+    for &(key, value) in &[("RECORD_ID_PREFIX", "web-access"), ("INCLUDE_INVOCATION_ID", "true"), ("INCLUDE_ARRIVAL_TS", "true")] {
+        let vars = |csv: bool| move |x: &str| match x {
+            "OUTPUT_FORMAT" if csv => Some("csv".to_string()),
+            _ if x == key => Some(value.to_string()),
+            _ => None,
+        };
+        let err = Config::from_vars(vars(true)).err().unwrap();
+        assert_eq!(err.to_string(), format!("invalid log format: OUTPUT_FORMAT=csv has no column for the field {} adds", key));
+        assert!(Config::from_vars(vars(false)).is_ok());
+    }
+    assert!(Config::from_vars(|x| if x == "INCLUDE_ARRIVAL_TS" { Some("false".to_string()) } else { None }).is_ok());
+}
+
+#[test]
+fn from_vars_invalid_field_map_test() {
+    let config = Config::from_vars(|key| match key {
+        "FIELD_MAP" => Some("[\"host\"]".to_string()),
+        _ => None,
+    });
+    assert!(config.is_err());
+}
+
+#[test]
+fn from_vars_log_format_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some("%h %>s".to_string()),
+        _ => None,
+    }).unwrap();
+    assert!(config.captures("7.248.7.119 200").is_some());
+}
+
+#[test]
+#[cfg(feature = "parallel")]
+fn from_vars_parallelism_test() {
+    let config = |n: &str| {
+        let n = n.to_string();
+        Config::from_vars(move |key| match key {
+            "PARALLELISM" => Some(n.clone()),
+            _ => None,
+        })
+    };
+
+    let sequential = config("1").unwrap();
+    assert!(sequential.pool().is_none());
+    assert_eq!(sequential.init_pool(), 1);
+    assert_eq!(sequential.map_records(&[1, 2, 3], |x| x * 2), vec![2, 4, 6]);
+
+    let pooled = config("2").unwrap();
+    assert_eq!(pooled.pool().map(ThreadPool::current_num_threads), Some(2));
+    assert_eq!(pooled.map_records(&[1, 2, 3], |x| x * 2), vec![2, 4, 6]);
+
+    assert!(config("0").is_err());
+    assert!(config("many").is_err());
+
+    let rayon_threads = Config::from_vars(|key| if key == "RAYON_THREADS" { Some("3".to_string()) } else { None }).unwrap();
+    assert_eq!(rayon_threads.init_pool(), 3);
+    let both = Config::from_vars(|key| match key {
+        "PARALLELISM" => Some("1".to_string()),
+        "RAYON_THREADS" => Some("3".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(both.threads(), 1);
+
+    let detected = Config::default();
+    assert!(detected.pool.get().is_none());
+    assert_eq!(detected.init_pool(), detected.threads());
+    assert_eq!(detected.threads(), thread::available_parallelism().unwrap().get());
+}
+
+#[test]
+fn enrich_concurrency_test() {
+    use std::time::Duration;
+
+    let config = Config::from_vars(|key| match key {
+        "PARALLELISM" => Some("8".to_string()).filter(|_| cfg!(feature = "parallel")),
+        "ENRICH_CONCURRENCY" => Some("2".to_string()),
+        _ => None,
+    }).unwrap();
+    let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let out = config.map_records(&(0..32).collect::<Vec<_>>(), |x| config.enrich(|| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        peak.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(2));
+        running.fetch_sub(1, Ordering::SeqCst);
+        x * 2
+    }));
+    assert_eq!(out, (0..32).map(|x| x * 2).collect::<Vec<_>>());
+    assert!(peak.load(Ordering::SeqCst) <= 2);
+    if cfg!(feature = "parallel") {
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    assert!(Config::from_vars(|key| if key == "ENRICH_CONCURRENCY" { Some("0".to_string()) } else { None }).is_err());
+}
+
+#[test]
+#[cfg(not(feature = "parallel"))]
+fn from_vars_parallelism_test() {
+    let config = |n: &str| {
+        let n = n.to_string();
+        Config::from_vars(move |key| match key {
+            "PARALLELISM" => Some(n.clone()),
+            _ => None,
+        })
+    };
+
+    assert_eq!(config("1").unwrap().map_records(&[1, 2, 3], |x| x * 2), vec![2, 4, 6]);
+    assert!(config("2").is_err());
+    assert!(config("0").is_err());
+}
@@ -0,0 +1,118 @@
+//! Elastic Common Schema documents for `OUTPUT_SCHEMA=ecs`: the fields of an
+//! `AccessLog` under the nested names Elasticsearch's ECS templates expect,
+//! such as `source.ip` and `http.response.status_code`.
+
+use serde_json::{self, Map, Value};
+
+use {split_request, AccessLog};
+
+/// The ECS release the field names follow.
+static ECS_VERSION: &str = "8.11.0";
+
+/// Builds the ECS document for `log`. Fields the line didn't have are left
+/// out rather than written as `null`.
+pub fn access_log(log: &AccessLog) -> Result<Value, serde_json::Error> {
+    let original = split_request(log.request).ok().and_then(|x| x.path);
+    let query = original.and_then(|x| x.find('?').map(|i| &x[i + 1..]));
+    let ip = log.client_ip.map(|x| x.to_string()).or_else(|| if log.host_is_ip { Some(log.host.to_string()) } else { None });
+    let geo = log.geo.as_ref().map(|x| serde_json::json!({
+        "country_iso_code": x.country,
+        "city_name": x.city,
+        "location": match (x.lat, x.lon) {
+            (Some(lat), Some(lon)) => serde_json::json!({"lat": lat, "lon": lon}),
+            _ => Value::Null,
+        },
+    }));
+
+    let mut doc = serde_json::json!({
+        "@timestamp": serde_json::to_value(&log.timestamp)?,
+        "ecs": {"version": ECS_VERSION},
+        "event": {
+            "duration": log.response_time_us.map(|x| x * 1000),
+            "original": log.raw,
+        },
+        "source": {
+            "address": log.host,
+            "ip": ip,
+            "geo": geo,
+        },
+        "user": {"name": log.authuser},
+        "http": {
+            "version": log.http_version,
+            "request": {
+                "method": log.method,
+                "referrer": log.referer.filter(|&x| x != "-"),
+                "bytes": log.bytes_received,
+            },
+            "response": {
+                "status_code": log.response,
+                "bytes": log.bytes_sent,
+                "body": {"bytes": log.bytes},
+            },
+        },
+        "url": {
+            "original": original,
+            "path": log.url_path,
+            "query": query,
+            "domain": log.vhost,
+        },
+        "server": {"port": log.port},
+        "user_agent": {
+            "original": log.user_agent,
+            "name": log.ua.as_ref().map(|x| x.browser),
+            "version": log.ua.as_ref().map(|x| x.browser_version),
+            "os": {"name": log.ua.as_ref().map(|x| x.os)},
+        },
+        "labels": log.headers,
+    });
+    prune(&mut doc);
+    Ok(doc)
+}
+
+/// Removes `null`s, and the objects left empty without them.
+fn prune(value: &mut Value) {
+    if let Value::Object(ref mut map) = *value {
+        for x in map.values_mut() {
+            prune(x);
+        }
+        let empty: Vec<String> = map.iter()
+            .filter(|&(_, x)| x.is_null() || x.as_object().is_some_and(Map::is_empty))
+            .map(|(k, _)| k.to_string())
+            .collect();
+        for k in empty {
+            map.remove(&k);
+        }
+    }
+}
+
+#[test]
+fn access_log_test() {
+    use config::Config;
+
+    let config = Config::from_vars(|key| if key == "OUTPUT_SCHEMA" { Some("ecs".to_string()) } else { None }).unwrap();
+    let line = r#"7.248.7.119 - frank [14/Dec/2017:22:16:45 +09:00] "GET /explore?q=rust HTTP/1.1" 200 9947 "-" "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1""#;
+    let a = ::log2json(&config, line).unwrap();
+
+    assert_eq!(a, serde_json::json!({
+        "@timestamp": "2017-12-14T22:16:45+09:00",
+        "ecs": {"version": ECS_VERSION},
+        "source": {"address": "7.248.7.119", "ip": "7.248.7.119"},
+        "user": {"name": "frank"},
+        "http": {
+            "version": "1.1",
+            "request": {"method": "GET"},
+            "response": {"status_code": 200, "body": {"bytes": 9947}},
+        },
+        "url": {"original": "/explore?q=rust", "path": "/explore", "query": "q=rust"},
+        "user_agent": {
+            "original": "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1",
+            "name": "Firefox",
+            "version": "8.5.1",
+            "os": {"name": "Windows 8"},
+        },
+    }));
+
+    let a = ::log2json(&config, r#"crawl.example.com - - [14/Dec/2017:22:16:45 +09:00] "GET /" 404 -"#).unwrap();
+    assert_eq!(a["source"], serde_json::json!({"address": "crawl.example.com"}));
+    assert_eq!(a["http"]["response"], serde_json::json!({"status_code": 404}));
+}
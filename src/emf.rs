@@ -0,0 +1,50 @@
+//! CloudWatch Embedded Metric Format documents, which CloudWatch Logs turns
+//! into metrics without a metric filter.
+
+use serde_json::{self, Value};
+
+/// Builds the EMF document for one invocation's record counts, with a
+/// single `region` dimension.
+pub fn document(namespace: &str, region: &str, ok: usize, failed: usize, dropped: usize, timestamp_millis: i64) -> Value {
+    serde_json::json!({
+        "_aws": {
+            "Timestamp": timestamp_millis,
+            "CloudWatchMetrics": [{
+                "Namespace": namespace,
+                "Dimensions": [["region"]],
+                "Metrics": [
+                    {"Name": "RecordsOk", "Unit": "Count"},
+                    {"Name": "RecordsFailed", "Unit": "Count"},
+                    {"Name": "RecordsDropped", "Unit": "Count"},
+                ],
+            }],
+        },
+        "region": region,
+        "RecordsOk": ok,
+        "RecordsFailed": failed,
+        "RecordsDropped": dropped,
+    })
+}
+
+/// Writes `doc` to stdout, where the Lambda log agent picks it up.
+pub fn emit(doc: &Value) {
+    println!("{}", doc);
+}
+
+#[test]
+fn document_test() {
+    let doc = document("FirehoseTransform", "ap-northeast-1", 2, 1, 1, 1513257405000);
+    let directive = &doc["_aws"]["CloudWatchMetrics"][0];
+
+    assert_eq!(doc["_aws"]["Timestamp"], 1513257405000i64);
+    assert_eq!(directive["Namespace"], "FirehoseTransform");
+    assert_eq!(directive["Dimensions"], serde_json::json!([["region"]]));
+    for metric in directive["Metrics"].as_array().unwrap() {
+        let name = metric["Name"].as_str().unwrap();
+        assert!(doc[name].is_number(), "{} has no value", name);
+    }
+    assert_eq!(doc["region"], "ap-northeast-1");
+    assert_eq!(doc["RecordsOk"], 2);
+    assert_eq!(doc["RecordsFailed"], 1);
+    assert_eq!(doc["RecordsDropped"], 1);
+}
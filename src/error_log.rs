@@ -0,0 +1,159 @@
+//! Apache error logs, in the 2.2 layout,
+//! `[Wed Oct 11 14:32:52 2000] [error] [client 127.0.0.1] message`, and the
+//! 2.4 one, which adds the module to the level, the process and thread, and
+//! the client's port.
+
+use std::net::IpAddr;
+
+use chrono::prelude::*;
+use regex::Regex;
+use serde_derive::Serialize;
+
+use {check_line_length, geoip, output_value, parse_ip, partition_keys_at, trim_line};
+use {LogError, Ndjson, RecordError, Timestamp, Transformed, OUTPUT_SIZE_RATIO};
+use config::Config;
+
+lazy_static! {
+    static ref ERROR_LOG_PATTERN: Regex = Regex::new(concat!(
+        r"^\[(?P<time>[^\]]+)\] ",
+        r"\[(?:(?P<module>[^:\]]+):)?(?P<level>[^\]]+)\]",
+        r"(?: \[pid (?P<pid>\d+)(?::tid (?P<tid>\d+))?\])?",
+        r"(?: \[client (?P<client>[^\]]+)\])?",
+        r" (?P<message>.*)$",
+    )).unwrap();
+}
+
+/// The bracketed time, which 2.4 writes with microseconds. It carries no
+/// offset, so it is read in `DEFAULT_TZ`, or else in UTC.
+static ERROR_TIME_FORMAT: &str = "%a %b %d %H:%M:%S%.f %Y";
+
+#[derive(Serialize, Debug)]
+pub struct ErrorLog<'a> {
+    #[serde(skip)]
+    pub time: DateTime<FixedOffset>,
+    #[serde(rename = "@timestamp")]
+    pub timestamp: Timestamp,
+    #[serde(rename = "@timestamp_utc")]
+    pub timestamp_utc: Timestamp,
+    /// `error`, `warn`, `notice` and so on, or 2.4's `trace1`-`trace8`.
+    pub level: &'a str,
+    /// The module that logged the line, e.g. `core`; 2.4 only.
+    pub module: Option<&'a str>,
+    pub pid: Option<u32>,
+    pub tid: Option<u64>,
+    pub client_ip: Option<IpAddr>,
+    pub client_port: Option<u16>,
+    pub message: &'a str,
+    /// The line exactly as received, when `RETAIN_RAW` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<&'a str>,
+}
+
+fn parse_error_time(config: &Config, s: &str) -> Result<DateTime<FixedOffset>, LogError> {
+    let naive = NaiveDateTime::parse_from_str(s, ERROR_TIME_FORMAT)?;
+    Ok(match config.default_tz {
+        Some(tz) => tz.from_local_datetime(&naive).earliest()
+            .ok_or_else(|| LogError::FormatError(format!("{} does not exist in {}", s, tz)))?
+            .fixed_offset(),
+        None => naive.and_utc().fixed_offset(),
+    })
+}
+
+/// Splits 2.4's `ip:port`, or takes 2.2's bare address.
+fn split_client(s: &str) -> Option<(IpAddr, Option<u16>)> {
+    if let Some(ip) = parse_ip(s) {
+        return Some((ip, None));
+    }
+    let i = s.rfind(':')?;
+    Some((parse_ip(&s[..i])?, Some(s[i + 1..].parse().ok()?)))
+}
+
+/// Parses one Apache error log line.
+pub fn parse_error_log<'a>(config: &Config, raw: &'a str) -> Result<ErrorLog<'a>, LogError> {
+    check_line_length(config, raw)?;
+    let s = trim_line(raw);
+    let xs = ERROR_LOG_PATTERN.captures(s).ok_or_else(|| LogError::unmatched(s))?;
+    let field = |name: &str| xs.name(name).map(|x| x.as_str());
+
+    let time = parse_error_time(config, field("time").unwrap_or_default())?;
+    let client = field("client").and_then(split_client);
+    let mut client_ip = client.map(|x| x.0);
+    if config.anonymize_ip {
+        client_ip = client_ip.map(geoip::anonymize);
+    }
+
+    Ok(ErrorLog {
+        time,
+        timestamp: Timestamp::new(config.timestamp_format, &time),
+        timestamp_utc: Timestamp::new(config.timestamp_format, &time.with_timezone(&Utc)),
+        level: field("level").unwrap_or_default(),
+        module: field("module"),
+        pid: field("pid").map(|x| x.parse()).transpose()?,
+        tid: field("tid").map(|x| x.parse()).transpose()?,
+        client_ip,
+        client_port: client.and_then(|x| x.1),
+        message: field("message").unwrap_or_default(),
+        raw: if config.retain_raw { Some(raw) } else { None },
+    })
+}
+
+/// `transform_data` for Apache error log records. Nothing is dropped:
+/// error lines have neither a status nor a user-agent to filter on.
+pub fn transform(config: &Config, s: &str) -> Result<Option<Transformed>, RecordError> {
+    let mut out = Ndjson::new(config, s.len() * OUTPUT_SIZE_RATIO);
+    for line in s.split('\n').filter(|x| !x.trim().is_empty()) {
+        match parse_error_log(config, line) {
+            Ok(log) => out.push_line(line, output_value(config, &log), || partition_keys_at(&log.time.with_timezone(&Utc), None))?,
+            Err(error) => out.fail(error, line),
+        }
+    }
+    out.finish()
+}
+
+#[test]
+fn parse_error_log_test() {
+    let log = parse_error_log(&Config::default(), "[Wed Oct 11 14:32:52 2000] [error] [client 127.0.0.1] client denied by server configuration: /export/home/live/ap/htdocs/test").unwrap();
+    assert_eq!(log.timestamp, Timestamp::Text("2000-10-11T14:32:52+00:00".to_string()));
+    assert_eq!(log.level, "error");
+    assert_eq!(log.module, None);
+    assert_eq!(log.client_ip, "127.0.0.1".parse::<IpAddr>().ok());
+    assert_eq!(log.client_port, None);
+    assert_eq!(log.message, "client denied by server configuration: /export/home/live/ap/htdocs/test");
+
+    let line = "[Fri Sep 09 10:42:29.902022 2011] [core:error] [pid 35708:tid 4328636416] [client 72.15.99.187:50000] AH00037: File does not exist: /usr/local/apache2/htdocs/favicon.ico";
+    let log = parse_error_log(&Config::default(), line).unwrap();
+    assert_eq!(log.timestamp, Timestamp::Text("2011-09-09T10:42:29.902022+00:00".to_string()));
+    assert_eq!(log.module, Some("core"));
+    assert_eq!(log.level, "error");
+    assert_eq!(log.pid, Some(35708));
+    assert_eq!(log.tid, Some(4328636416));
+    assert_eq!(log.client_ip, "72.15.99.187".parse::<IpAddr>().ok());
+    assert_eq!(log.client_port, Some(50000));
+    assert_eq!(log.message, "AH00037: File does not exist: /usr/local/apache2/htdocs/favicon.ico");
+
+    let log = parse_error_log(&Config::default(), "[Wed Oct 11 14:32:52 2000] [notice] Apache/2.2.34 configured -- resuming normal operations").unwrap();
+    assert_eq!(log.client_ip, None);
+    assert_eq!(log.message, "Apache/2.2.34 configured -- resuming normal operations");
+
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    assert_eq!(parse_error_log(&Config::default(), line).unwrap_err().kind(), "RegexParseError");
+}
+
+#[test]
+fn transform_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("apache_error".to_string()),
+        "DEFAULT_TZ" => Some("Asia/Tokyo".to_string()),
+        _ => None,
+    }).unwrap();
+    let data = b"[Wed Oct 11 14:32:52 2000] [error] [client 127.0.0.1] File does not exist: /favicon.ico\ngarbage\n";
+    let out = ::transform_data(&config, data.to_vec()).unwrap().unwrap();
+    let line: ::serde_json::Value = ::serde_json::from_slice(&out.data).unwrap();
+
+    assert_eq!(line["@timestamp"], "2000-10-11T14:32:52+09:00");
+    assert_eq!(line["@timestamp_utc"], "2000-10-11T05:32:52+00:00");
+    assert_eq!(line["level"], "error");
+    assert_eq!(line["client_ip"], "127.0.0.1");
+    assert_eq!(out.partition_keys["day"], "11");
+    assert_eq!(out.partition_keys.get("status_class"), None);
+}
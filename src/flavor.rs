@@ -0,0 +1,187 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use regex::{Captures, Regex};
+
+use LogError;
+use clf::{self, ClfFields};
+use logformat::header_field;
+
+/// Apache escapes quotes and backslashes in the request as `\"` and `\\`,
+/// so a backslash takes the next character with it and can't end the
+/// request.
+static APACHE_PATTERN: &str = r#"^(?P<host>\S+) (?P<ident>\S+) (?P<authuser>\S+) \[(?P<time>[\w:/.]+(?:\s[\+\-]\d{2}:?\d{2})?){0,1}\] "(?P<request>(?:\\.|[^\\\n])+?)" (?P<status>\d{3}) (?P<bytes>\d+|-)(?: "(?P<referer>[^"]*)" "(?P<user_agent>[^"]*)")?"#;
+/// `APACHE_PATTERN` for configs that log the referer and user-agent without
+/// their quotes, where a space in either would shift the columns. They are
+/// found by position instead: the referer is the first word after the byte
+/// count, and the user-agent everything after it, quoted or not.
+static APACHE_UNQUOTED_TAIL_PATTERN: &str = r#"^(?P<host>\S+) (?P<ident>\S+) (?P<authuser>\S+) \[(?P<time>[\w:/.]+(?:\s[\+\-]\d{2}:?\d{2})?){0,1}\] "(?P<request>(?:\\.|[^\\\n])+?)" (?P<status>\d{3}) (?P<bytes>\d+|-)(?: "?(?P<referer>[^"\s]*)"?(?: "?(?P<user_agent>.*?)"?)?)?$"#;
+static APACHE_TIME_FORMATS: &[&str] = &["%d/%b/%Y:%H:%M:%S %:z", "%d/%b/%Y:%H:%M:%S %z", "%d/%b/%Y:%H:%M:%S%.3f %z", "%d/%b/%Y:%H:%M:%S"];
+
+static NGINX_PATTERN: &str = r#"^(?P<host>\S+) - (?P<authuser>\S+) \[(?P<time>[^\]]+)\] "(?P<request>[^"]*)" (?P<status>\d{3}) (?P<bytes>\d+) "(?P<referer>[^"]*)" "(?P<user_agent>[^"]*)""#;
+static NGINX_TIME_FORMATS: &[&str] = &["%d/%b/%Y:%H:%M:%S %z"];
+
+/// The access log dialect a stream carries, selected by `LOG_FLAVOR`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFlavor {
+    /// Apache Common or Combined Log Format.
+    Apache,
+    /// nginx's default `combined` format.
+    Nginx,
+}
+
+impl LogFlavor {
+    /// Every flavor, in the order `LOG_FLAVOR=auto` tries them.
+    pub fn all() -> &'static [LogFlavor] {
+        &[LogFlavor::Apache, LogFlavor::Nginx]
+    }
+
+    /// Default line pattern, with the same named groups as `compile_format`.
+    pub fn pattern(&self) -> &'static str {
+        match *self {
+            LogFlavor::Apache => APACHE_PATTERN,
+            LogFlavor::Nginx => NGINX_PATTERN,
+        }
+    }
+
+    /// `pattern`, or for Apache `APACHE_UNQUOTED_TAIL_PATTERN`, under
+    /// `UNQUOTED_TAIL`.
+    pub fn unquoted_tail_pattern(&self) -> &'static str {
+        match *self {
+            LogFlavor::Apache => APACHE_UNQUOTED_TAIL_PATTERN,
+            LogFlavor::Nginx => NGINX_PATTERN,
+        }
+    }
+
+    /// `chrono` formats tried in order against the `time` capture. Formats
+    /// without an offset only apply when `DEFAULT_TZ` is set.
+    pub fn time_formats(&self) -> &'static [&'static str] {
+        match *self {
+            LogFlavor::Apache => APACHE_TIME_FORMATS,
+            LogFlavor::Nginx => NGINX_TIME_FORMATS,
+        }
+    }
+}
+
+/// A compiled line pattern together with the `chrono` formats tried in order
+/// against its `time` capture.
+pub struct LineFormat {
+    pub pattern: Regex,
+    /// Try `clf::scan` before `pattern`. Set when `pattern` is the built-in
+    /// Apache one, which the scanner matches exactly.
+    pub scanner: bool,
+    /// `(group, field)` for each header group of `pattern` that isn't a
+    /// field of its own, as named by `compile_format`.
+    pub headers: Vec<(String, String)>,
+    pub time_formats: Vec<String>,
+    /// Index into `time_formats` of the last one that parsed, tried first.
+    last_time_format: AtomicUsize,
+}
+
+impl LineFormat {
+    pub fn new(pattern: Regex, flavor: LogFlavor) -> LineFormat {
+        let headers = pattern.capture_names()
+            .flatten()
+            .filter_map(|x| header_field(x).map(|field| (x.to_string(), field.to_string())))
+            .collect();
+        LineFormat {
+            scanner: pattern.as_str() == APACHE_PATTERN,
+            headers,
+            pattern,
+            time_formats: flavor.time_formats().iter().map(|x| x.to_string()).collect(),
+            last_time_format: AtomicUsize::new(0),
+        }
+    }
+
+    /// The groups of `line`, from the scanner when it applies or else the
+    /// regex.
+    pub fn captures<'t>(&self, line: &'t str) -> Option<Fields<'t>> {
+        if self.scanner {
+            if let Some(xs) = clf::scan(line) {
+                return Some(Fields::Clf(xs));
+            }
+        }
+        self.pattern.captures(line).map(Fields::Regex)
+    }
+
+    /// `time_formats` with their indices, starting with the one that parsed
+    /// last time.
+    pub fn ordered_time_formats(&self) -> impl Iterator<Item = (usize, &str)> {
+        let last = self.last_time_format.load(Ordering::Relaxed);
+        self.time_formats.get(last).map(|x| (last, x.as_str())).into_iter()
+            .chain(self.time_formats.iter().enumerate().filter(move |&(i, _)| i != last).map(|(i, x)| (i, x.as_str())))
+    }
+
+    /// Records that `time_formats[i]` parsed, so it is tried first next time.
+    pub fn set_last_time_format(&self, i: usize) {
+        if self.last_time_format.load(Ordering::Relaxed) != i {
+            self.last_time_format.store(i, Ordering::Relaxed);
+        }
+    }
+
+    /// Forgets which time format parsed last.
+    pub fn reset_last_time_format(&self) {
+        self.last_time_format.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The named groups of a matched line.
+pub enum Fields<'t> {
+    Regex(Captures<'t>),
+    Clf(ClfFields<'t>),
+}
+
+impl<'t> Fields<'t> {
+    pub fn name(&self, name: &str) -> Option<&'t str> {
+        match *self {
+            Fields::Regex(ref xs) => xs.name(name).map(|x| x.as_str()),
+            Fields::Clf(ref xs) => xs.name(name),
+        }
+    }
+}
+
+impl From<LogFlavor> for LineFormat {
+    fn from(flavor: LogFlavor) -> LineFormat {
+        LineFormat::new(Regex::new(flavor.pattern()).unwrap(), flavor)
+    }
+}
+
+impl FromStr for LogFlavor {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<LogFlavor, LogError> {
+        match s.to_lowercase().as_str() {
+            "apache" => Ok(LogFlavor::Apache),
+            "nginx" => Ok(LogFlavor::Nginx),
+            _ => Err(LogError::FormatError(format!("unknown log flavor {}", s))),
+        }
+    }
+}
+
+#[test]
+fn from_str_test() {
+    assert_eq!("apache".parse::<LogFlavor>().unwrap(), LogFlavor::Apache);
+    assert_eq!("Nginx".parse::<LogFlavor>().unwrap(), LogFlavor::Nginx);
+    assert!("iis".parse::<LogFlavor>().is_err());
+}
+
+#[test]
+fn scanner_test() {
+    assert!(LineFormat::from(LogFlavor::Apache).scanner);
+    assert!(!LineFormat::from(LogFlavor::Nginx).scanner);
+    assert!(!LineFormat::new(Regex::new(r"^(?P<host>\S+)").unwrap(), LogFlavor::Apache).scanner);
+}
+
+#[test]
+fn ordered_time_formats_test() {
+    let format = LineFormat::from(LogFlavor::Apache);
+    let order = |format: &LineFormat| format.ordered_time_formats().map(|(i, _)| i).collect::<Vec<_>>();
+    assert_eq!(order(&format), vec![0, 1, 2, 3]);
+
+    format.set_last_time_format(1);
+    assert_eq!(order(&format), vec![1, 0, 2, 3]);
+    assert_eq!(format.ordered_time_formats().next(), Some((1, APACHE_TIME_FORMATS[1])));
+
+    format.reset_last_time_format();
+    assert_eq!(order(&format), vec![0, 1, 2, 3]);
+}
@@ -0,0 +1,112 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use maxminddb::{geoip2, Reader};
+use serde_derive::{Serialize, Deserialize};
+
+use LogError;
+use util::retry;
+
+/// Tries given to a lookup that errors, and the wait after the first.
+static LOOKUP_ATTEMPTS: u32 = 3;
+static LOOKUP_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Location of a client address, as attached to the `geo` output field.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+/// A MaxMind GeoLite2/GeoIP2 City database loaded into memory.
+pub struct GeoDb {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoDb {
+    pub fn open(path: &str) -> Result<GeoDb, LogError> {
+        Ok(GeoDb { reader: Reader::open_readfile(path)? })
+    }
+
+    /// Looks up `ip`, returning `None` for anything that isn't a public
+    /// address or isn't in the database. A lookup that errors is retried,
+    /// then logged and also taken as `None`, so that the line goes out
+    /// without `geo` rather than failing.
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        if !is_public(&ip) {
+            return None;
+        }
+
+        retry(|| self.try_lookup(ip), LOOKUP_ATTEMPTS, LOOKUP_BACKOFF)
+            .unwrap_or_else(|e| {
+                tracing::warn!(error_type = e.kind(), "geo lookup of {} failed: {}", ip, e);
+                None
+            })
+    }
+
+    fn try_lookup(&self, ip: IpAddr) -> Result<Option<GeoInfo>, LogError> {
+        let city: Option<geoip2::City> = self.reader.lookup(ip)?.decode()?;
+        Ok(city.map(|city| GeoInfo {
+            country: city.country.iso_code.map(|x| x.to_string()),
+            city: city.city.names.english.map(|x| x.to_string()),
+            lat: city.location.latitude,
+            lon: city.location.longitude,
+        }))
+    }
+}
+
+/// Whether `ip` is routable on the internet, as opposed to private,
+/// loopback, link-local and the like.
+pub fn is_public(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => {
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation())
+        }
+        IpAddr::V6(ref ip) => {
+            let head = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified()
+                || head & 0xfe00 == 0xfc00
+                || head & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Zeroes the last octet of an IPv4 address, or the last 80 bits of an IPv6
+/// one, which still locates it to about a city.
+pub fn anonymize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let xs = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(xs[0], xs[1], xs[2], 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[test]
+fn anonymize_test() {
+    assert_eq!(anonymize("7.248.7.119".parse().unwrap()), "7.248.7.0".parse::<IpAddr>().unwrap());
+    assert_eq!(anonymize("2001:db8:85a3:8d3:1319:8a2e:370:7348".parse().unwrap()), "2001:db8:85a3::".parse::<IpAddr>().unwrap());
+}
+
+#[test]
+fn is_public_test() {
+    assert!(is_public(&"7.248.7.119".parse().unwrap()));
+    assert!(is_public(&"2607:f8b0::1".parse().unwrap()));
+    assert!(!is_public(&"10.0.0.1".parse().unwrap()));
+    assert!(!is_public(&"192.168.1.1".parse().unwrap()));
+    assert!(!is_public(&"127.0.0.1".parse().unwrap()));
+    assert!(!is_public(&"fd00::1".parse().unwrap()));
+    assert!(!is_public(&"fe80::1".parse().unwrap()));
+}
+
+#[test]
+fn open_missing_database_test() {
+    assert!(GeoDb::open("/nonexistent/GeoLite2-City.mmdb").is_err());
+}
@@ -0,0 +1,3218 @@
+//! Access log parsing and the Firehose, Kinesis and S3 transforms built on
+//! it. The Lambda entry point lives in `main.rs`.
+
+extern crate tracing;
+#[cfg(test)]
+extern crate tracing_subscriber;
+
+extern crate chrono;
+extern crate chrono_tz;
+extern crate data_encoding;
+
+extern crate serde;
+extern crate serde_json;
+extern crate serde_derive;
+
+extern crate regex;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+extern crate percent_encoding;
+extern crate thiserror;
+extern crate flate2;
+extern crate encoding_rs;
+extern crate csv;
+#[cfg(feature = "compression")]
+extern crate snap;
+#[cfg(feature = "compression")]
+extern crate zstd;
+extern crate maxminddb;
+extern crate woothee;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+extern crate rusoto_sns;
+extern crate tokio;
+extern crate tokio_util;
+
+pub mod alb;
+pub mod alert;
+pub mod clf;
+pub mod cloudfront;
+pub mod config;
+pub mod ecs;
+pub mod emf;
+pub mod error_log;
+pub mod flavor;
+pub mod geoip;
+pub mod logformat;
+pub mod metrics;
+pub mod rules;
+pub mod s3;
+pub mod useragent;
+pub mod util;
+pub mod w3c;
+
+use serde_derive::{Serialize, Deserialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::net::IpAddr;
+use chrono::prelude::*;
+use data_encoding::{BASE64, BASE64URL, BASE64URL_NOPAD};
+use percent_encoding::percent_decode_str;
+use flate2::read::MultiGzDecoder;
+use config::{Config, OutputFormat, OutputSchema, Parser, SnappyFraming, TextEncoding, TimestampFormat, TimestampMode};
+use flavor::{Fields, LineFormat};
+use geoip::GeoInfo;
+use useragent::UserAgent;
+
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum LogError {
+    /// Carries the start of the text that didn't match, cut to
+    /// `MAX_UNMATCHED_BYTES`.
+    #[error("FAIL. unmatched pattern: {0}")]
+    RegexParseError(String),
+    #[error(transparent)]
+    UTF8Error(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    EncodingError(#[from] data_encoding::DecodeError),
+    #[error(transparent)]
+    DateTimeParseError(#[from] chrono::ParseError),
+    #[error(transparent)]
+    IntError(#[from] std::num::ParseIntError),
+    #[error(transparent)]
+    FloatError(#[from] std::num::ParseFloatError),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    /// A parsed line that couldn't be written as JSON.
+    #[error("could not serialize output: {0}")]
+    SerializeError(serde_json::Error),
+    #[error(transparent)]
+    PatternError(#[from] regex::Error),
+    #[error("invalid log format: {0}")]
+    FormatError(String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    GeoIpError(#[from] maxminddb::MaxMindDbError),
+    #[error("S3 request failed: {0}")]
+    S3Error(String),
+    #[error("SNS publish failed: {0}")]
+    SnsError(String),
+    /// A status outside `100..=599`, usually a sign that the pattern
+    /// matched the wrong field.
+    #[error("invalid status {0}")]
+    InvalidStatus(u16),
+    #[error("record has no data")]
+    EmptyRecord,
+    /// The base64 output would be over `MAX_RECORD_BYTES`, so Firehose
+    /// would reject it.
+    #[error("transformed record is {0} bytes, over MAX_RECORD_BYTES")]
+    RecordTooLarge(usize),
+    /// A line over `MAX_LINE_BYTES`, refused before any pattern runs on it.
+    #[error("line is {0} bytes, over MAX_LINE_BYTES")]
+    LineTooLong(usize),
+}
+
+#[test]
+fn log_error_display_test() {
+    assert_eq!(LogError::RegexParseError("garbage".to_string()).to_string(), "FAIL. unmatched pattern: garbage");
+    assert_eq!(LogError::FormatError("%Z".to_string()).to_string(), "invalid log format: %Z");
+}
+
+impl LogError {
+    /// `RegexParseError` for `s`, keeping only its first
+    /// `MAX_UNMATCHED_BYTES`.
+    fn unmatched(s: &str) -> LogError {
+        LogError::RegexParseError(truncate(s, MAX_UNMATCHED_BYTES).to_string())
+    }
+
+    /// The variant name, as reported in `ErrorDetail::error_type`.
+    fn kind(&self) -> &'static str {
+        match *self {
+            LogError::RegexParseError(_) => "RegexParseError",
+            LogError::UTF8Error(_) => "UTF8Error",
+            LogError::EncodingError(_) => "EncodingError",
+            LogError::DateTimeParseError(_) => "DateTimeParseError",
+            LogError::IntError(_) => "IntError",
+            LogError::FloatError(_) => "FloatError",
+            LogError::JsonError(_) => "JsonError",
+            LogError::SerializeError(_) => "SerializeError",
+            LogError::PatternError(_) => "PatternError",
+            LogError::FormatError(_) => "FormatError",
+            LogError::IoError(_) => "IoError",
+            LogError::GeoIpError(_) => "GeoIpError",
+            LogError::S3Error(_) => "S3Error",
+            LogError::SnsError(_) => "SnsError",
+            LogError::InvalidStatus(_) => "InvalidStatus",
+            LogError::EmptyRecord => "EmptyRecord",
+            LogError::RecordTooLarge(_) => "RecordTooLarge",
+            LogError::LineTooLong(_) => "LineTooLong",
+        }
+    }
+}
+
+/// A record that failed to transform, along with the first line that failed
+/// to parse when the failure is line-specific.
+#[derive(Debug)]
+pub struct RecordError {
+    pub error: LogError,
+    pub line: Option<String>,
+}
+
+impl From<LogError> for RecordError {
+    fn from(error: LogError) -> RecordError {
+        RecordError { error, line: None }
+    }
+}
+
+#[test]
+fn regex_parse_error_truncated_test() {
+    let line = "x".repeat(MAX_UNMATCHED_BYTES * 2);
+    match log2json(&Config::default(), &line).unwrap_err() {
+        LogError::RegexParseError(x) => assert_eq!(x.len(), MAX_UNMATCHED_BYTES),
+        e => panic!("unexpected {:?}", e),
+    }
+}
+
+/// `Display` for `RegexParseError` used to call itself and overflow the stack.
+#[test]
+fn regex_parse_error_display_regression_test() {
+    assert_eq!(format!("{}", LogError::RegexParseError("x".to_string())), "FAIL. unmatched pattern: x");
+
+    let err = log2json(&Config::default(), "garbage").unwrap_err();
+    assert_eq!(format!("{}", err), "FAIL. unmatched pattern: garbage");
+}
+
+/// Parses one line according to the configured `LogFlavor` into its output
+/// object.
+pub fn log2json(config: &Config, s: &str) -> Result<serde_json::Value, LogError> {
+    let log = parse_access_log(config, s)?;
+    access_log2json(config, &log)
+}
+
+/// Parses lines one at a time with whichever parser `LOG_FLAVOR` selects,
+/// as the local modes read them. A W3C or CloudFront `#Fields:` directive
+/// sets the columns of the lines after it.
+pub struct LineParser<'c> {
+    config: &'c Config,
+    w3c_fields: Option<Vec<String>>,
+}
+
+impl<'c> LineParser<'c> {
+    pub fn new(config: &'c Config) -> LineParser<'c> {
+        LineParser { config, w3c_fields: None }
+    }
+
+    /// The output object of `line`, or `None` for a W3C directive.
+    pub fn parse(&mut self, line: &str) -> Result<Option<serde_json::Value>, LogError> {
+        let config = self.config;
+        match config.parser {
+            Parser::Access => log2json(config, line).map(Some),
+            Parser::Alb => output_value(config, &alb::parse_alb_log(config, line)?).map(Some),
+            Parser::ApacheError => output_value(config, &error_log::parse_error_log(config, line)?).map(Some),
+            Parser::W3c | Parser::CloudFront => {
+                if line.starts_with('#') {
+                    if let Some(xs) = w3c::fields_directive(line) {
+                        self.w3c_fields = Some(xs);
+                    }
+                    return Ok(None);
+                }
+                let fields = self.w3c_fields.as_ref().ok_or_else(w3c::no_fields)?;
+                let (mut map, _) = w3c::parse_row(config, fields, line)?;
+                if config.parser == Parser::CloudFront {
+                    cloudfront::decode_row(&mut map);
+                }
+                shape_output(config, &mut map);
+                Ok(Some(serde_json::Value::Object(map)))
+            }
+        }
+    }
+}
+
+#[test]
+fn line_parser_test() {
+    let config = Config::from_vars(|key| if key == "LOG_FLAVOR" { Some("w3c".to_string()) } else { None }).unwrap();
+    let mut parser = LineParser::new(&config);
+    assert_eq!(parser.parse("2017-12-14 13:16:45 10.0.0.5 GET /").unwrap_err().kind(), "FormatError");
+    assert_eq!(parser.parse("#Fields: date time c-ip cs-method").unwrap(), None);
+    let a = parser.parse("2017-12-14 13:16:45 7.248.7.119 GET").unwrap().unwrap();
+    assert_eq!(a["c-ip"], "7.248.7.119");
+    assert_eq!(a["@timestamp"], "2017-12-14T13:16:45+00:00");
+
+    let config = Config::from_vars(|key| if key == "LOG_FLAVOR" { Some("apache_error".to_string()) } else { None }).unwrap();
+    let a = LineParser::new(&config).parse("[Wed Oct 11 14:32:52 2000] [error] [client 127.0.0.1] denied").unwrap().unwrap();
+    assert_eq!(a["level"], "error");
+}
+
+/// Serializes a parsed line into the output object, in the schema set by
+/// `OUTPUT_SCHEMA`, applying `FIELD_MAP` renames.
+pub fn access_log2json(config: &Config, log: &AccessLog) -> Result<serde_json::Value, LogError> {
+    let value = match config.output_schema {
+        OutputSchema::Flat => serde_json::to_value(log),
+        OutputSchema::Ecs => ecs::access_log(log),
+    };
+    Ok(shaped(config, value.map_err(LogError::SerializeError)?))
+}
+
+/// Serializes a parsed line of any parser into its output object, applying
+/// `shape_output`.
+fn output_value<T: serde::Serialize>(config: &Config, log: &T) -> Result<serde_json::Value, LogError> {
+    Ok(shaped(config, serde_json::to_value(log).map_err(LogError::SerializeError)?))
+}
+
+fn shaped(config: &Config, mut value: serde_json::Value) -> serde_json::Value {
+    if let serde_json::Value::Object(ref mut map) = value {
+        shape_output(config, map);
+    }
+    value
+}
+
+#[test]
+fn serialize_error_test() {
+    // serde_json writes non-finite floats as `null` rather than failing, so
+    // a map with non-string keys stands in for a line that can't be written.
+    assert_eq!(serde_json::to_value(f64::NAN).unwrap(), serde_json::Value::Null);
+    let unwritable: BTreeMap<(u8, u8), u8> = vec![((1, 2), 3)].into_iter().collect();
+    let error = output_value(&Config::default(), &unwritable).unwrap_err();
+    assert_eq!(error.kind(), "SerializeError");
+    assert_eq!(error.to_string(), "could not serialize output: key must be a string");
+
+    let config = Config::default();
+    let good = log2json(&config, r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#);
+    let mut out = Ndjson::new(&config, 0);
+    out.push_line("bad", output_value(&config, &unwritable), BTreeMap::new).unwrap();
+    out.push_line("good", good, BTreeMap::new).unwrap();
+    let data = out.finish().unwrap().unwrap().data;
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&data).unwrap()["host"], "7.248.7.119");
+
+    let mut out = Ndjson::new(&config, 0);
+    out.push_line("bad", output_value(&config, &unwritable), BTreeMap::new).unwrap();
+    let err = out.finish().unwrap_err();
+    assert_eq!(err.error.kind(), "SerializeError");
+    assert_eq!(err.line.as_deref(), Some("bad"));
+}
+
+/// Runs the `TRANSFORM_RULES` over an output object, removes the timestamp
+/// `TIMESTAMP_MODE` leaves out and the `EXCLUDE_FIELDS`, then applies the
+/// `FIELD_MAP` renames to what is left.
+fn shape_output(config: &Config, map: &mut serde_json::Map<String, serde_json::Value>) {
+    rules::apply(&config.transform_rules, map);
+    match config.timestamp_mode {
+        TimestampMode::Both => {}
+        TimestampMode::LocalOnly => {
+            map.remove("@timestamp_utc");
+        }
+        TimestampMode::UtcOnly if map.contains_key("@timestamp_utc") => {
+            map.remove("@timestamp");
+        }
+        TimestampMode::UtcOnly => {}
+    }
+    for field in &config.exclude_fields {
+        map.remove(field);
+    }
+    for (from, to) in &config.field_map {
+        if let Some(x) = map.remove(from) {
+            map.insert(to.to_string(), x);
+        }
+    }
+}
+
+#[test]
+fn transform_rules_test() {
+    let config = Config::from_vars(|key| match key {
+        "TRANSFORM_RULES" => Some("lower(method); strip_port(host)".to_string()),
+        "LOG_PATTERN" => Some(r#"^(?P<host>\S+) \[(?P<time>.+?)\] "(?P<request>.+?)" (?P<status>\d+)"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let a = log2json(&config, r#"7.248.7.119:8080 [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200"#).unwrap();
+
+    assert_eq!(a["method"], "get");
+    assert_eq!(a["host"], "7.248.7.119");
+    assert_eq!(a["request"], "GET /explore");
+}
+
+#[test]
+fn exclude_fields_test() {
+    let config = Config::from_vars(|key| match key {
+        "EXCLUDE_FIELDS" => Some("host, authuser,status_class,@timestamp".to_string()),
+        "FIELD_MAP" => Some(r#"{"@timestamp": "ts"}"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"7.248.7.119 - frank [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let out = transform_data(&config, data.as_bytes().to_vec()).unwrap().unwrap();
+    let a: serde_json::Value = serde_json::from_slice(&out.data).unwrap();
+
+    assert!(a.get("host").is_none());
+    assert!(a.get("authuser").is_none());
+    assert!(a.get("status_class").is_none());
+    assert!(a.get("ts").is_none());
+    assert_eq!(a["response"], 200);
+}
+
+/// Parses one line against the configured formats, ignoring the spaces and
+/// line terminators around it.
+pub fn parse_access_log<'a>(config: &Config, raw: &'a str) -> Result<AccessLog<'a>, LogError> {
+    check_line_length(config, raw)?;
+    let s = trim_line(raw);
+    let (format, xs) = config.captures(s).ok_or_else(|| LogError::unmatched(s))?;
+    let field = |name| xs.name(name).ok_or_else(|| LogError::unmatched(s));
+
+    let time = parse_time(config, format, field("time")?)?;
+
+    let request = split_request(field("request")?)?;
+
+    let mut host = Cow::Borrowed(field("host")?);
+    let mut ip = parse_ip(&host);
+    let mut client_ip = field("xff").ok().and_then(first_public_ip);
+    if config.anonymize_ip {
+        ip = ip.map(geoip::anonymize);
+        client_ip = client_ip.map(geoip::anonymize);
+        if let Some(ip) = ip {
+            host = Cow::Owned(ip.to_string());
+        }
+    }
+    let known_user_agent = field("user_agent").ok().and_then(dash_as_none);
+    let ua = known_user_agent.and_then(useragent::parse);
+
+    let response = field("status")?.parse::<u16>()?;
+    if !(100..=599).contains(&response) {
+        return Err(LogError::InvalidStatus(response));
+    }
+
+    let mut parse_warnings = Vec::new();
+    let port = tolerate(config, &mut parse_warnings, "port", field("port").ok().map(|x| x.parse::<u16>()).transpose());
+    let bytes = tolerate(config, &mut parse_warnings, "bytes", field("bytes").or_else(|_| field("bytes_sent")).ok().and_then(dash_as_none).map(|x| x.parse::<u64>()).transpose());
+    let byte_count = |name| field(name).ok().map(|x| if x == "-" { Ok(0) } else { x.parse::<u64>() }).transpose();
+    let bytes_received = tolerate(config, &mut parse_warnings, "bytes_received", byte_count("bytes_received"));
+    let bytes_sent = tolerate(config, &mut parse_warnings, "bytes_sent", byte_count("bytes_sent"));
+    let response_time_us = tolerate(config, &mut parse_warnings, "response_time_us", response_time_us(&field));
+
+    Ok(AccessLog {
+        time,
+        host,
+        vhost: field("vhost").ok(),
+        port: port?,
+        host_is_ip: ip.is_some(),
+        ip_version: ip.map(|x| if x.is_ipv4() { 4 } else { 6 }),
+        client_ip,
+        ident: field("ident").ok().and_then(|x| config.dash(x)),
+        authuser: field("authuser").ok().and_then(|x| config.dash(x)),
+        timestamp: Timestamp::new(config.timestamp_format, &time),
+        timestamp_utc: Timestamp::new(config.timestamp_format, &time.with_timezone(&Utc)),
+        request: field("request")?,
+        method: request.method,
+        path: request.path.map(decode_path),
+        url_path: request.path.map(url_path),
+        protocol: request.protocol,
+        http_version: request.protocol.and_then(http_version),
+        query: request.path.map(parse_query).unwrap_or_default(),
+        response,
+        status_class: status_class(response),
+        bytes: bytes?,
+        bytes_received: bytes_received?,
+        bytes_sent: bytes_sent?,
+        response_time_us: response_time_us?,
+        referer: field("referer").ok().and_then(|x| config.dash(x)),
+        user_agent: field("user_agent").ok().and_then(|x| config.dash(x)),
+        is_bot: ua.as_ref().is_some_and(UserAgent::is_crawler) || known_user_agent.is_some_and(|x| config.is_bot_ua(x)),
+        ua,
+        geo: config.geoip.as_ref().and_then(|db| client_ip.or(ip).and_then(|x| config.enrich(|| db.lookup(x)))),
+        raw: if config.retain_raw { Some(raw) } else { None },
+        headers: headers(config, format, &xs),
+        parse_warnings,
+    })
+}
+
+/// Under `LENIENT`, an optional field that fails to parse is taken as
+/// missing, with its name added to `warnings`, rather than failing the line.
+fn tolerate<T, E>(config: &Config, warnings: &mut Vec<&'static str>, name: &'static str, x: Result<Option<T>, E>) -> Result<Option<T>, E> {
+    match x {
+        Err(_) if config.lenient => {
+            warnings.push(name);
+            Ok(None)
+        }
+        x => x,
+    }
+}
+
+#[test]
+fn lenient_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 99999999999999999999999"#;
+    assert_eq!(log2json(&Config::default(), data).unwrap_err().kind(), "IntError");
+    assert!(log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#)
+        .unwrap().get("_parse_warnings").is_none());
+
+    let config = Config::from_vars(|key| match key {
+        "LENIENT" => Some("true".to_string()),
+        _ => None,
+    }).unwrap();
+    let a = log2json(&config, data).unwrap();
+    assert_eq!(a["bytes"], serde_json::Value::Null);
+    assert_eq!(a["_parse_warnings"], serde_json::json!(["bytes"]));
+    assert_eq!(a["response"], 200);
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 999 9947"#;
+    assert_eq!(log2json(&config, data).unwrap_err().kind(), "InvalidStatus");
+}
+
+/// Collects `format`'s header groups by field, turning repeats into arrays.
+fn headers(config: &Config, format: &LineFormat, xs: &Fields) -> BTreeMap<String, serde_json::Value> {
+    let mut headers = BTreeMap::new();
+    for (group, field) in &format.headers {
+        let value = match xs.name(group) {
+            Some(x) => serde_json::Value::from(config.dash(x)),
+            None => continue,
+        };
+        match headers.entry(field.to_string()) {
+            Entry::Vacant(x) => {
+                x.insert(value);
+            }
+            Entry::Occupied(mut x) => match *x.get_mut() {
+                serde_json::Value::Array(ref mut xs) => xs.push(value),
+                ref mut first => *first = serde_json::Value::Array(vec![first.take(), value]),
+            },
+        }
+    }
+    headers
+}
+
+#[test]
+fn headers_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some(r#"%h %t \"%r\" %>s %b \"%{X-Custom}i\" \"%{X-Custom}i\" \"%{X-Request-Id}i\""#.to_string()),
+        _ => None,
+    }).unwrap();
+    let line = r#"7.248.7.119 [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "a" "b" "42""#;
+    let a = log2json(&config, line).unwrap();
+
+    assert_eq!(a["x_custom"], serde_json::json!(["a", "b"]));
+    assert_eq!(a["x_request_id"], "42");
+    assert!(a.get("header_1_x_custom").is_none());
+}
+
+/// The client address when `host` is an IPv4 or IPv6 address, with or
+/// without brackets, rather than a host name.
+fn parse_ip(host: &str) -> Option<IpAddr> {
+    let host = if host.starts_with('[') && host.ends_with(']') { &host[1..host.len() - 1] } else { host };
+    host.parse().ok()
+}
+
+#[test]
+fn anonymize_ip_test() {
+    let config = Config::from_vars(|key| match key {
+        "ANONYMIZE_IP" => Some("true".to_string()),
+        "LOG_FORMAT" => Some(r#"%h %t \"%r\" %>s %b \"%{X-Forwarded-For}i\""#.to_string()),
+        _ => None,
+    }).unwrap();
+    let line = |host, xff| format!(r#"{} [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "{}""#, host, xff);
+
+    let a = log2json(&config, &line("7.248.7.119", "8.8.4.4, 10.0.0.1")).unwrap();
+    assert_eq!(a["host"], "7.248.7.0");
+    assert_eq!(a["client_ip"], "8.8.4.0");
+
+    let a = log2json(&config, &line("2001:db8:85a3:8d3:1319:8a2e:370:7348", "-")).unwrap();
+    assert_eq!(a["host"], "2001:db8:85a3::");
+    assert_eq!(a["ip_version"], 6);
+
+    let a = log2json(&config, &line("crawl-66-249-66-1.googlebot.com", "-")).unwrap();
+    assert_eq!(a["host"], "crawl-66-249-66-1.googlebot.com");
+}
+
+#[test]
+fn host_test() {
+    let line = |host| format!(r#"{} - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#, host);
+
+    let a = log2json(&Config::default(), &line("7.248.7.119")).unwrap();
+    assert_eq!(a["host_is_ip"], true);
+    assert_eq!(a["ip_version"], 4);
+
+    let a = log2json(&Config::default(), &line("2001:db8::1")).unwrap();
+    assert_eq!(a["host"], "2001:db8::1");
+    assert_eq!(a["host_is_ip"], true);
+    assert_eq!(a["ip_version"], 6);
+
+    let a = log2json(&Config::default(), &line("[2001:db8::1]")).unwrap();
+    assert_eq!(a["host"], "[2001:db8::1]");
+    assert_eq!(a["ip_version"], 6);
+
+    let a = log2json(&Config::default(), &line("crawl-66-249-66-1.googlebot.com")).unwrap();
+    assert_eq!(a["host"], "crawl-66-249-66-1.googlebot.com");
+    assert_eq!(a["host_is_ip"], false);
+    assert_eq!(a["ip_version"], serde_json::Value::Null);
+}
+
+/// The first public address in an `X-Forwarded-For` chain, skipping
+/// entries that are private or not addresses at all.
+fn first_public_ip(xff: &str) -> Option<IpAddr> {
+    xff.split(',')
+        .filter_map(|x| parse_ip(x.trim()))
+        .find(geoip::is_public)
+}
+
+#[test]
+fn client_ip_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some(r#"%h %l %u %t \"%r\" %>s %b \"%{X-Forwarded-For}i\""#.to_string()),
+        _ => None,
+    }).unwrap();
+    let line = |xff| format!(r#"10.0.0.1 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 "{}""#, xff);
+
+    let a = log2json(&config, &line("unknown, 192.168.0.5, 7.248.7.119, 10.0.0.2")).unwrap();
+    assert_eq!(a["host"], "10.0.0.1");
+    assert_eq!(a["client_ip"], "7.248.7.119");
+
+    let a = log2json(&config, &line("[2607:f8b0::1], 7.248.7.119")).unwrap();
+    assert_eq!(a["client_ip"], "2607:f8b0::1");
+
+    let a = log2json(&config, &line("-")).unwrap();
+    assert_eq!(a["client_ip"], serde_json::Value::Null);
+
+    let a = log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap();
+    assert_eq!(a["client_ip"], serde_json::Value::Null);
+}
+
+#[test]
+fn vhost_port_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some(r#"%v:%p %h %l %u %t \"%r\" %>s %b"#.to_string()),
+        _ => None,
+    }).unwrap();
+
+    let a = log2json(&config, r#"www.example.com:443 7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap();
+    assert_eq!(a["vhost"], "www.example.com");
+    assert_eq!(a["port"], 443);
+    assert_eq!(a["host"], "7.248.7.119");
+
+    let err = log2json(&config, r#"www.example.com:99999 7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap_err();
+    assert_eq!(err.kind(), "IntError");
+
+    let a = log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap();
+    assert!(a.get("vhost").is_none());
+    assert!(a.get("port").is_none());
+}
+
+/// The request duration in microseconds, from whichever of the
+/// `response_time_us`, `response_time_ms` and `response_time_s` captures
+/// the format has.
+fn response_time_us<'a, F>(field: &F) -> Result<Option<u64>, LogError>
+    where F: Fn(&'static str) -> Result<&'a str, LogError>
+{
+    let units = [("response_time_us", 1), ("response_time_ms", 1_000), ("response_time_s", 1_000_000)];
+    for &(name, scale) in &units {
+        if let Some(x) = field(name).ok().and_then(dash_as_none) {
+            return Ok(Some(x.parse::<u64>()? * scale));
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn response_time_test() {
+    let config = |format: &str| {
+        let format = format.to_string();
+        Config::from_vars(move |key| match key {
+            "LOG_FORMAT" => Some(format.clone()),
+            _ => None,
+        }).unwrap()
+    };
+
+    let a = log2json(&config(r#"%h %l %u %t \"%r\" %>s %b %D"#),
+                     r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 1532"#).unwrap();
+    assert_eq!(a["response_time_us"], 1532);
+
+    let a = log2json(&config(r#"%h %l %u %t \"%r\" %>s %b %T"#),
+                     r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 2"#).unwrap();
+    assert_eq!(a["response_time_us"], 2_000_000);
+
+    let a = log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap();
+    assert!(a.get("response_time_us").is_none());
+}
+
+/// Apache logs a literal `-` for values it doesn't know.
+fn dash_as_none(s: &str) -> Option<&str> {
+    if s == "-" { None } else { Some(s) }
+}
+
+#[test]
+fn dash_handling_test() {
+    let line = r#"7.248.7.119 - frank [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "-" "-""#;
+    let json = |handling: Option<&str>| {
+        let config = Config::from_vars(|key| match key {
+            "DASH_HANDLING" => handling.map(|x| x.to_string()),
+            _ => None,
+        }).unwrap();
+        log2json(&config, line).unwrap()
+    };
+
+    for handling in &[None, Some("null")] {
+        let a = json(*handling);
+        assert!(a["ident"].is_null());
+        assert!(a["referer"].is_null());
+        assert!(a["user_agent"].is_null());
+        assert_eq!(a["authuser"], "frank");
+    }
+
+    let a = json(Some("empty"));
+    assert_eq!(a["ident"], "");
+    assert_eq!(a["referer"], "");
+    assert_eq!(a["authuser"], "frank");
+    assert_eq!(a["is_bot"], false);
+
+    let a = json(Some("Literal"));
+    assert_eq!(a["ident"], "-");
+    assert_eq!(a["user_agent"], "-");
+    assert!(a.get("ua").is_none());
+
+    let config = Config::from_vars(|key| match key {
+        "DASH_HANDLING" => Some("empty".to_string()),
+        "LOG_FORMAT" => Some(r#"%h %l %u %t \"%r\" %>s %b \"%{X-Request-Id}i\""#.to_string()),
+        _ => None,
+    }).unwrap();
+    let a = log2json(&config, r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "-""#).unwrap();
+    assert_eq!(a["x_request_id"], "");
+
+    assert!(Config::from_vars(|key| match key {
+        "DASH_HANDLING" => Some("dash".to_string()),
+        _ => None,
+    }).is_err());
+}
+
+/// Whether a parsed line should be discarded instead of delivered, e.g. load
+/// balancer health checks, crawlers when `DROP_CRAWLERS` is set, paths
+/// matching `DROP_PATH_REGEX`, or statuses outside `STATUS_FILTER`.
+fn should_drop(config: &Config, log: &AccessLog) -> bool {
+    log.user_agent.is_some_and(|x| x.contains("ELB-HealthChecker"))
+        || (config.drop_crawlers && log.is_bot)
+        || config.drop_path_regex.as_ref().is_some_and(|re| log.url_path.as_ref().is_some_and(|x| re.is_match(x)))
+        || !config.keeps_status(log.response)
+}
+
+/// Tries each of the format's time formats, starting with the one that
+/// parsed last. Timestamps without an offset are interpreted in
+/// `DEFAULT_TZ` when it is set.
+fn parse_time(config: &Config, format: &LineFormat, s: &str) -> Result<DateTime<FixedOffset>, LogError> {
+    let mut result = Err(LogError::unmatched(s));
+    for (i, time_format) in format.ordered_time_formats() {
+        result = DateTime::parse_from_str(s, time_format).map_err(LogError::DateTimeParseError);
+        if result.is_ok() {
+            format.set_last_time_format(i);
+            break;
+        }
+
+        if let Some(tz) = config.default_tz {
+            if let Ok(naive) = NaiveDateTime::parse_from_str(s, time_format) {
+                if let Some(time) = tz.from_local_datetime(&naive).earliest() {
+                    format.set_last_time_format(i);
+                    return Ok(time.fixed_offset());
+                }
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn fractional_seconds_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45.123 +09:00] "GET /explore" 200 9947"#;
+    let a = log2json(&Config::default(), data).unwrap();
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45.123+09:00");
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45.123+00:00");
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45.123 +0900] "GET /explore" 200 9947"#;
+    assert_eq!(log2json(&Config::default(), data).unwrap()["@timestamp"], "2017-12-14T22:16:45.123+09:00");
+}
+
+#[test]
+fn unquoted_tail_test() {
+    let config = Config::from_vars(|key| match key {
+        "UNQUOTED_TAIL" => Some("true".to_string()),
+        _ => None,
+    }).unwrap();
+    assert!(!config.formats[0].scanner);
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 http://example.com/ Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101 Firefox/8.5.1"#;
+    let a = log2json(&config, data).unwrap();
+    assert_eq!(a["referer"], "http://example.com/");
+    assert_eq!(a["user_agent"], "Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101 Firefox/8.5.1");
+    assert_eq!(a["ua"]["browser"], "Firefox");
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 "-" "curl/7.58.0 (x86_64)""#;
+    let a = log2json(&config, data).unwrap();
+    assert!(a["referer"].is_null());
+    assert_eq!(a["user_agent"], "curl/7.58.0 (x86_64)");
+
+    let a = log2json(&config, r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap();
+    assert!(a["user_agent"].is_null());
+
+    for (key, value) in &[("LOG_PATTERN", r"^(?P<host>\S+)"), ("LOG_FORMAT", "%h"), ("FIELD_SEPARATOR", "tab")] {
+        let conflict = Config::from_vars(|k| match k {
+            "UNQUOTED_TAIL" => Some("true".to_string()),
+            _ if k == *key => Some(value.to_string()),
+            _ => None,
+        });
+        assert_eq!(conflict.err().unwrap().kind(), "FormatError");
+    }
+}
+
+#[test]
+fn timestamp_formats_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_PATTERN" => Some(r#"^(?P<host>\S+) \[(?P<time>.+?)\] "(?P<request>.+?)" (?P<status>\d+)"#.to_string()),
+        "TIMESTAMP_FORMATS" => Some("%Y-%m-%dT%H:%M:%S%.f%:z\n%d.%m.%Y %H:%M:%S %z".to_string()),
+        _ => None,
+    }).unwrap();
+    let line = |time| format!(r#"7.248.7.119 [{}] "GET /explore" 200"#, time);
+
+    assert_eq!(log2json(&config, &line("2017-12-14T22:16:45.250+09:00")).unwrap()["@timestamp"], "2017-12-14T22:16:45.250+09:00");
+    assert_eq!(log2json(&config, &line("14.12.2017 22:16:45 +0900")).unwrap()["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(log2json(&config, &line("14/Dec/2017:22:16:45 +09:00")).unwrap_err().kind(), "DateTimeParseError");
+}
+
+#[test]
+fn parse_time_remembers_format_test() {
+    let config = Config::from_vars(|key| match key {
+        "DEFAULT_TZ" => Some("Asia/Tokyo".to_string()),
+        _ => None,
+    }).unwrap();
+    let format = &config.formats[0];
+
+    let naive = parse_time(&config, format, "14/Dec/2017:22:16:45").unwrap();
+    assert_eq!(format.ordered_time_formats().next().map(|(i, _)| i), Some(3));
+
+    let offset = parse_time(&config, format, "14/Dec/2017:22:16:45 +09:00").unwrap();
+    assert_eq!(naive, offset);
+    assert_eq!(format.ordered_time_formats().next().map(|(i, _)| i), Some(0));
+}
+
+struct RequestLine<'a> {
+    method: &'a str,
+    path: Option<&'a str>,
+    protocol: Option<&'a str>,
+}
+
+fn split_request<'a>(s: &'a str) -> Result<RequestLine<'a>, LogError> {
+    let s = s.trim();
+    let (method, rest) = match s.find(' ') {
+        Some(i) => (&s[..i], s[i + 1..].trim_start()),
+        None => (s, ""),
+    };
+    if method.is_empty() {
+        return Err(LogError::unmatched(s));
+    }
+
+    let (path, protocol) = match rest.rfind(' ') {
+        Some(i) if rest[i + 1..].starts_with("HTTP/") => (rest[..i].trim_end(), Some(&rest[i + 1..])),
+        _ => (rest, None),
+    };
+
+    Ok(RequestLine {
+        method,
+        path: if path.is_empty() { None } else { Some(path) },
+        protocol,
+    })
+}
+
+#[test]
+fn split_request_test() {
+    let r = split_request("GET /explore HTTP/1.1").unwrap();
+    assert_eq!(r.method, "GET");
+    assert_eq!(r.path, Some("/explore"));
+    assert_eq!(r.protocol, Some("HTTP/1.1"));
+
+    let r = split_request("GET /explore").unwrap();
+    assert_eq!(r.method, "GET");
+    assert_eq!(r.path, Some("/explore"));
+    assert_eq!(r.protocol, None);
+
+    assert!(split_request(" ").is_err());
+}
+
+/// The HTTP version a request line's protocol names, with HTTP/2's and
+/// HTTP/3's optional `.0` dropped.
+fn http_version(protocol: &str) -> Option<&'static str> {
+    match protocol {
+        "HTTP/1.0" => Some("1.0"),
+        "HTTP/1.1" => Some("1.1"),
+        "HTTP/2" | "HTTP/2.0" => Some("2"),
+        "HTTP/3" | "HTTP/3.0" => Some("3"),
+        _ => None,
+    }
+}
+
+#[test]
+fn http_version_test() {
+    let log = parse_access_log(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "PRI * HTTP/2.0" 200 9947"#).unwrap();
+    assert_eq!(log.method, "PRI");
+    assert_eq!(log.path.as_deref(), Some("*"));
+    assert_eq!(log.protocol, Some("HTTP/2.0"));
+    assert_eq!(log.http_version, Some("2"));
+
+    let a = log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/3" 200 9947"#).unwrap();
+    assert_eq!(a["protocol"], "HTTP/3");
+    assert_eq!(a["http_version"], "3");
+
+    let log = parse_access_log(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/9.9" 200 9947"#).unwrap();
+    assert_eq!(log.protocol, Some("HTTP/9.9"));
+    assert_eq!(log.http_version, None);
+
+    assert_eq!(http_version("HTTP/1.0"), Some("1.0"));
+    assert_eq!(http_version("HTTP/1.1"), Some("1.1"));
+    assert_eq!(http_version("HTTP/2"), Some("2"));
+    assert_eq!(http_version("HTTP/3.0"), Some("3"));
+}
+
+#[test]
+fn escaped_quote_request_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /a\" 200 1 HTTP/1.1" 200 9947 "-" "curl/7.46.0""#;
+    for config in &[Config::default(), Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some(r#"%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\""#.to_string()),
+        _ => None,
+    }).unwrap()] {
+        let log = parse_access_log(config, line).unwrap();
+        assert_eq!(log.request, r#"GET /a\" 200 1 HTTP/1.1"#);
+        assert_eq!(log.path.as_deref(), Some(r#"/a\" 200 1"#));
+        assert_eq!(log.protocol, Some("HTTP/1.1"));
+        assert_eq!(log.response, 200);
+        assert_eq!(log.bytes, Some(9947));
+        assert_eq!(log.user_agent, Some("curl/7.46.0"));
+    }
+}
+
+fn parse_query(path: &str) -> BTreeMap<String, String> {
+    match path.find('?') {
+        Some(i) => query_params(&path[i + 1..]),
+        None => BTreeMap::new(),
+    }
+}
+
+/// The parameters of a query string, each key and value decoded once the
+/// string is split, so that an encoded `&` or `=` stays inside its value.
+fn query_params(query: &str) -> BTreeMap<String, String> {
+    query.split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.find('=') {
+            Some(i) => (decode_component(&x[..i]), decode_component(&x[i + 1..])),
+            None => (decode_component(x), String::new()),
+        })
+        .collect()
+}
+
+/// `path` with the part before its query string percent-decoded, except
+/// for `%3F` and `%23`, which would read as the start of a query or
+/// fragment. A `%` not followed by two hex digits stays as it is, and so
+/// does the whole path if it decodes to invalid UTF-8.
+fn decode_path(path: &str) -> Cow<'_, str> {
+    let end = path.find('?').unwrap_or(path.len());
+    let b = &path.as_bytes()[..end];
+    let hex = |i: usize| b.get(i).and_then(|&x| (x as char).to_digit(16));
+
+    let mut decoded = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match (b[i], hex(i + 1), hex(i + 2)) {
+            (b'%', Some(hi), Some(lo)) => {
+                match (hi * 16 + lo) as u8 {
+                    b'?' | b'#' => decoded.extend_from_slice(&b[i..i + 3]),
+                    x => decoded.push(x),
+                }
+                i += 3;
+            }
+            (x, _, _) => {
+                decoded.push(x);
+                i += 1;
+            }
+        }
+    }
+    if decoded == b {
+        return Cow::Borrowed(path);
+    }
+    match String::from_utf8(decoded) {
+        Ok(decoded) => Cow::Owned(decoded + &path[end..]),
+        Err(_) => Cow::Borrowed(path),
+    }
+}
+
+#[test]
+fn decode_path_test() {
+    assert_eq!(decode_path("/my%20docs/a+b.html?q=a%20b"), "/my docs/a+b.html?q=a%20b");
+    assert_eq!(decode_path("/files/a%2Fb"), "/files/a/b");
+    assert_eq!(decode_path("/100%ZZ/%2"), "/100%ZZ/%2");
+    assert_eq!(decode_path("/%FF"), "/%FF");
+    assert_eq!(decode_path("/what%3F/is%3f%23this?q=1"), "/what%3F/is%3f%23this?q=1");
+    assert_eq!(decode_path("/a%20b%3Fc"), "/a b%3Fc");
+    assert!(matches!(decode_path("/explore?q=rust"), Cow::Borrowed(_)));
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /my%20docs/a%2Fb%22c?q=%ZZ&r=a%20b HTTP/1.1" 200 9947"#;
+    let log = parse_access_log(&Config::default(), data).unwrap();
+    assert_eq!(log.path.as_deref(), Some(r#"/my docs/a/b"c?q=%ZZ&r=a%20b"#));
+    assert_eq!(log.request, "GET /my%20docs/a%2Fb%22c?q=%ZZ&r=a%20b HTTP/1.1");
+    assert_eq!(log.query["r"], "a b");
+
+    let a = log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /100%ZZ HTTP/1.1" 200 9947"#).unwrap();
+    assert_eq!(a["path"], "/100%ZZ");
+}
+
+/// The path of a request target, without its query string or fragment and
+/// percent-decoded. `*`, as in `OPTIONS *`, is kept as it is.
+fn url_path(path: &str) -> String {
+    let end = path.find(['?', '#']).unwrap_or(path.len());
+    percent_decode_str(&path[..end]).decode_utf8_lossy().into_owned()
+}
+
+#[test]
+fn is_bot_test() {
+    let line = |ua| format!(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "-" "{}""#, ua);
+    let googlebot = line("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+    let firefox = line("Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1");
+    let monitor = line("acme-synthetics/1.4 (status checks)");
+    let common = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+
+    let config = Config::default();
+    assert_eq!(log2json(&config, &googlebot).unwrap()["is_bot"], true);
+    assert_eq!(log2json(&config, &firefox).unwrap()["is_bot"], false);
+    assert_eq!(log2json(&config, &monitor).unwrap()["is_bot"], false);
+    assert_eq!(log2json(&config, common).unwrap()["is_bot"], false);
+
+    let config = Config::from_vars(|key| match key {
+        "BOT_UA_PATTERNS" => Some(r#"["(?i)^acme-synthetics/", "^curl/\\d"]"#.to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(log2json(&config, &monitor).unwrap()["is_bot"], true);
+    assert_eq!(log2json(&config, &line("curl/7.46.0")).unwrap()["is_bot"], true);
+    assert_eq!(log2json(&config, &googlebot).unwrap()["is_bot"], true);
+    assert_eq!(log2json(&config, &firefox).unwrap()["is_bot"], false);
+}
+
+#[test]
+fn url_path_test() {
+    assert_eq!(url_path("/search?q=x"), "/search");
+    assert_eq!(url_path("/"), "/");
+    assert_eq!(url_path("*"), "*");
+    assert_eq!(url_path("/my%20docs/a+b.html#top"), "/my docs/a+b.html");
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "OPTIONS * HTTP/1.1" 200 0"#;
+    assert_eq!(log2json(&Config::default(), data).unwrap()["url_path"], "*");
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /search?q=x HTTP/1.1" 200 0"#;
+    let a = log2json(&Config::default(), data).unwrap();
+    assert_eq!(a["url_path"], "/search");
+    assert_eq!(a["path"], "/search?q=x");
+}
+
+fn decode_component(s: &str) -> String {
+    percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned()
+}
+
+#[test]
+fn parse_query_test() {
+    let q = parse_query("/search?q=rust%20lang&page=1&page=2&flag");
+    assert_eq!(q.len(), 3);
+    assert_eq!(q["q"], "rust lang");
+    assert_eq!(q["page"], "2");
+    assert_eq!(q["flag"], "");
+
+    assert!(parse_query("/explore").is_empty());
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let a = log2json(&Config::default(), data).unwrap();
+    assert_eq!(a["query"], serde_json::json!({}));
+}
+
+/// Parses every non-empty line of `data` and joins the results as NDJSON.
+/// Lines that fail to parse are skipped; the record only fails when none of
+/// its lines parse, in which case the first error is returned. Returns
+/// `None` when every line matched `should_drop`. Data that starts with `{`
+/// is taken to be JSON already and only normalized. Each line is wrapped in
+/// `OUTPUT_ENVELOPE` when one is configured.
+pub fn transform_data(config: &Config, data: Vec<u8>) -> std::result::Result<Option<Transformed>, RecordError> {
+    let s = decode_text(config, decompress(config, data)?)?;
+    if s.trim().is_empty() {
+        return Err(LogError::EmptyRecord.into());
+    }
+
+    if s.trim_start().starts_with('{') {
+        return transform_json(config, &s);
+    }
+    match config.parser {
+        Parser::Access => transform_access_logs(config, &s),
+        Parser::W3c => w3c::transform(config, &s),
+        Parser::Alb => alb::transform(config, &s),
+        Parser::CloudFront => cloudfront::transform(config, &s),
+        Parser::ApacheError => error_log::transform(config, &s),
+    }
+}
+
+fn transform_access_logs(config: &Config, s: &str) -> Result<Option<Transformed>, RecordError> {
+    let mut out = Ndjson::new(config, s.len() * OUTPUT_SIZE_RATIO);
+    for line in s.split('\n').filter(|x| !x.trim().is_empty()) {
+        match parse_access_log(config, line) {
+            Ok(ref log) if should_drop(config, log) => {}
+            Ok(log) => out.push_line(line, access_log2json(config, &log), || partition_keys(&log))?,
+            Err(error) => out.fail(error, line),
+        }
+    }
+    out.finish()
+}
+
+/// Records that are already JSON objects, one or more, are passed through
+/// re-serialized, with `status_class` added from a numeric `response` or
+/// `status` and `FIELD_MAP` applied. Objects are dropped as `json_should_drop`
+/// says, and failed, like lines, when over `MAX_LINE_BYTES`.
+fn transform_json(config: &Config, s: &str) -> Result<Option<Transformed>, RecordError> {
+    let mut out = Ndjson::new(config, s.len());
+    let mut values = serde_json::Deserializer::from_str(s).into_iter::<serde_json::Value>();
+    loop {
+        let start = values.byte_offset();
+        let value = match values.next() {
+            Some(x) => x,
+            None => break,
+        };
+        let text = s[start..values.byte_offset()].trim();
+        let mut map = match value {
+            Ok(serde_json::Value::Object(x)) => x,
+            Ok(_) => {
+                out.fail(LogError::FormatError("record is JSON but not an object".to_string()), text);
+                continue;
+            }
+            Err(e) => {
+                // Nothing after a syntax error can be told apart, so the
+                // rest of its line stands for the object.
+                let text = s[start..].trim_start();
+                out.fail(e.into(), text.split('\n').next().unwrap_or(text));
+                break;
+            }
+        };
+        if let Err(e) = check_line_length(config, text) {
+            out.fail(e, text);
+            continue;
+        }
+
+        let status = map.get("response").or_else(|| map.get("status"))
+            .and_then(|x| x.as_u64())
+            .and_then(|x| u16::try_from(x).ok())
+            .filter(|x| (100..600).contains(x));
+        if json_should_drop(config, &map, status) {
+            continue;
+        }
+        if let Some(status) = status {
+            map.entry("status_class").or_insert_with(|| serde_json::Value::from(status_class(status)));
+        }
+        let time = map.get("@timestamp")
+            .and_then(|x| x.as_str())
+            .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+            .map(|x| x.with_timezone(&Utc));
+
+        shape_output(config, &mut map);
+        out.push(&serde_json::Value::Object(map), || time.map(|x| partition_keys_at(&x, status)).unwrap_or_default())?;
+    }
+    out.finish()
+}
+
+/// `should_drop` for JSON objects: crawlers by `is_bot` or `user_agent`,
+/// `DROP_PATH_REGEX` on `url_path` or else the path of `path`, and
+/// statuses outside `STATUS_FILTER`.
+fn json_should_drop(config: &Config, map: &serde_json::Map<String, serde_json::Value>, status: Option<u16>) -> bool {
+    let text = |key| map.get(key).and_then(|x: &serde_json::Value| x.as_str());
+    let crawler = config.drop_crawlers
+        && (map.get("is_bot") == Some(&serde_json::Value::Bool(true))
+            || text("user_agent").and_then(useragent::parse).is_some_and(|x| x.is_crawler()));
+    let path = config.drop_path_regex.as_ref().is_some_and(|re| {
+        text("url_path").map(Cow::Borrowed).or_else(|| text("path").map(|x| Cow::Owned(url_path(x))))
+            .is_some_and(|x| re.is_match(&x))
+    });
+    crawler || path || status.is_some_and(|x| !config.keeps_status(x))
+}
+
+#[test]
+fn transform_json_test() {
+    let data = br#"  {"host": "7.248.7.119", "@timestamp": "2017-12-14T22:16:45+09:00", "response": 404, "path": "/explore"}
+{"host": "7.248.7.120", "status": 200, "status_class": "ok"}"#;
+    let out = transform_data(&Config::default(), data.to_vec()).unwrap().unwrap();
+    let lines: Vec<serde_json::Value> = String::from_utf8(out.data).unwrap()
+        .split('\n')
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["path"], "/explore");
+    assert_eq!(lines[0]["status_class"], "4xx");
+    assert_eq!(lines[1]["status_class"], "ok");
+    assert_eq!(out.partition_keys["day"], "14");
+    assert_eq!(out.partition_keys["status_class"], "4xx");
+
+    let err = transform_data(&Config::default(), br#"{"host": "#.to_vec()).unwrap_err();
+    assert_eq!(err.error.kind(), "JsonError");
+}
+
+#[test]
+fn transform_json_filters_test() {
+    let data = br#"{"host": "a", "response": 70000}
+{"host": "b", "status": 65736}
+{"host": "c", "path": "/app.js?v=1"}
+{"host": "d", "user_agent": "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"}
+{"host": "e", "is_bot": true}
+{"host": "f", "url_path": "/api"}"#;
+    let config = Config::from_vars(|key| match key {
+        "STATUS_FILTER" => Some("2xx".to_string()),
+        "DROP_PATH_REGEX" => Some(r"\.js$".to_string()),
+        "DROP_CRAWLERS" => Some("true".to_string()),
+        _ => None,
+    }).unwrap();
+    let out = transform_data(&config, data.to_vec()).unwrap().unwrap();
+    let hosts: Vec<serde_json::Value> = String::from_utf8(out.data).unwrap()
+        .split('\n')
+        .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()["host"].clone())
+        .collect();
+    assert_eq!(hosts, vec!["a", "b", "f"]);
+
+    let config = Config::from_vars(|key| if key == "MAX_LINE_BYTES" { Some("24".to_string()) } else { None }).unwrap();
+    let out = transform_data(&config, br#"{"host": "b"} {"host": "aaaaaaaaaaaaaaaa"}"#.to_vec()).unwrap().unwrap();
+    assert_eq!(String::from_utf8(out.data).unwrap(), r#"{"host":"b"}"#);
+
+    // Failures name the object rather than the whole record.
+    let err = transform_data(&Config::default(), b"[1]\n{\"host\": \"a\"".to_vec()).unwrap_err();
+    assert_eq!(err.line.as_deref(), Some("[1]"));
+    let err = transform_data(&Config::default(), b"{\"host\": \n\"a\"".to_vec()).unwrap_err();
+    assert_eq!(err.line.as_deref(), Some(r#"{"host": "#));
+}
+
+#[test]
+fn output_envelope_test() {
+    let config = Config::from_vars(|key| match key {
+        "OUTPUT_ENVELOPE" => Some(r#"{"source": "apache", "event": "{{event}}"}"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = br#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947
+{"host": "7.248.7.120"}"#;
+    let out = transform_data(&config, data.to_vec()).unwrap().unwrap();
+    let line: serde_json::Value = serde_json::from_slice(&out.data).unwrap();
+    assert_eq!(line["source"], "apache");
+    assert_eq!(line["event"]["host"], "7.248.7.119");
+    assert_eq!(line["event"]["response"], 200);
+
+    let out = transform_data(&config, br#"{"host": "7.248.7.120"}"#.to_vec()).unwrap().unwrap();
+    assert_eq!(String::from_utf8(out.data).unwrap(), r#"{"event":{"host":"7.248.7.120"},"source":"apache"}"#);
+
+    let invalid = Config::from_vars(|key| match key {
+        "OUTPUT_ENVELOPE" => Some(r#"{"event": {{event}}}"#.to_string()),
+        _ => None,
+    });
+    assert_eq!(invalid.err().unwrap().kind(), "JsonError");
+}
+
+/// Columns of `OUTPUT_FORMAT=csv` rows, by their default field names.
+pub static CSV_COLUMNS: &[&str] = &[
+    "@timestamp", "host", "ident", "authuser", "request", "method", "path", "protocol", "response", "bytes",
+    "referer", "user_agent",
+];
+
+/// One CSV row, without its line terminator.
+fn write_csv_row<T: AsRef<[u8]>>(out: &mut Vec<u8>, row: &[T]) -> Result<(), LogError> {
+    let mut writer = csv::WriterBuilder::new().terminator(csv::Terminator::Any(b'\n')).from_writer(&mut *out);
+    writer.write_record(row).map_err(|e| LogError::FormatError(e.to_string()))?;
+    writer.flush()?;
+    drop(writer);
+    out.pop();
+    Ok(())
+}
+
+/// A field as a CSV cell: strings as they are, `null` or a missing field as
+/// empty, and anything else as JSON.
+fn csv_cell(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(x)) => x.to_string(),
+        Some(x) => x.to_string(),
+    }
+}
+
+#[test]
+fn csv_output_test() {
+    let config = Config::from_vars(|key| match key {
+        "OUTPUT_FORMAT" => Some("csv".to_string()),
+        "CSV_HEADER" => Some("true".to_string()),
+        "FIELD_MAP" => Some(r#"{"@timestamp": "ts"}"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = concat!(
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /search?q="a,b" HTTP/1.1" 200 9947 "-" "curl/7.46.0""#, "\n",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /" 404 -"#,
+    );
+    let out = transform_data(&config, data.as_bytes().to_vec()).unwrap().unwrap();
+    let lines: Vec<&str> = std::str::from_utf8(&out.data).unwrap().split('\n').collect();
+
+    assert_eq!(lines, vec![
+        "ts,host,ident,authuser,request,method,path,protocol,response,bytes,referer,user_agent",
+        r#"2017-12-14T22:16:45+09:00,7.248.7.119,,,"GET /search?q=""a,b"" HTTP/1.1",GET,"/search?q=""a,b""",HTTP/1.1,200,9947,,curl/7.46.0"#,
+        "2017-12-14T22:16:46+09:00,7.248.7.120,,,GET /,GET,/,,404,,,",
+    ]);
+    assert_eq!(out.partition_keys["day"], "14");
+}
+
+/// A record's output being built up line by line, along with the partition
+/// keys of its first line and the first line that failed.
+struct Ndjson<'c> {
+    config: &'c Config,
+    data: Vec<u8>,
+    /// Lines pushed so far, including any already taken by `take_data`.
+    lines: usize,
+    partition_keys: Option<BTreeMap<String, String>>,
+    first_err: Option<RecordError>,
+}
+
+impl<'c> Ndjson<'c> {
+    fn new(config: &'c Config, capacity: usize) -> Ndjson<'c> {
+        Ndjson {
+            config,
+            data: Vec::with_capacity(capacity),
+            lines: 0,
+            partition_keys: None,
+            first_err: None,
+        }
+    }
+
+    /// Writes `value` as the next line. A line that can't be written is
+    /// taken back out, so the output stays well formed.
+    fn push<F>(&mut self, value: &serde_json::Value, partition_keys: F) -> Result<(), LogError>
+        where F: FnOnce() -> BTreeMap<String, String>
+    {
+        let start = self.data.len();
+        self.write(value).inspect_err(|_| self.data.truncate(start))?;
+        self.partition_keys.get_or_insert_with(partition_keys);
+        self.lines += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, value: &serde_json::Value) -> Result<(), LogError> {
+        if self.lines == 0 && self.config.output_format == OutputFormat::Csv && self.config.csv_header {
+            let header: Vec<&str> = CSV_COLUMNS.iter().map(|x| self.config.output_name(x)).collect();
+            write_csv_row(&mut self.data, &header)?;
+        }
+        if self.lines > 0 || !self.data.is_empty() {
+            self.data.push(b'\n');
+        }
+        match (self.config.output_format, &self.config.output_envelope) {
+            (OutputFormat::Csv, _) => {
+                let row: Vec<String> = CSV_COLUMNS.iter().map(|x| csv_cell(value.get(self.config.output_name(x)))).collect();
+                write_csv_row(&mut self.data, &row)?;
+            }
+            (OutputFormat::Json, Some(envelope)) => envelope.write(&mut self.data, value)?,
+            (OutputFormat::Json, None) => serde_json::to_writer(&mut self.data, value).map_err(LogError::SerializeError)?,
+        }
+        Ok(())
+    }
+
+    /// The output written since the last call, for writers that stream it
+    /// out as they go. Lines after it start with their separator.
+    fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    /// `push` for the output of `line`, or a failed `line` when it couldn't
+    /// be serialized, so that one unwritable line doesn't take the rest of
+    /// the record with it.
+    fn push_line<F>(&mut self, line: &str, value: Result<serde_json::Value, LogError>, partition_keys: F) -> Result<(), LogError>
+        where F: FnOnce() -> BTreeMap<String, String>
+    {
+        match value {
+            Ok(value) => self.push(&value, partition_keys),
+            Err(error) => {
+                self.fail(error, line);
+                Ok(())
+            }
+        }
+    }
+
+    fn fail(&mut self, error: LogError, line: &str) {
+        self.first_err.get_or_insert(RecordError { error, line: Some(line.to_string()) });
+    }
+
+    /// The output, unless no line made it in: then the first error, or
+    /// `None` when every line was dropped.
+    fn finish(self) -> Result<Option<Transformed>, RecordError> {
+        match self.first_err {
+            Some(e) if self.lines == 0 => Err(e),
+            _ if self.lines == 0 => Ok(None),
+            _ => Ok(Some(Transformed { data: self.data, partition_keys: self.partition_keys.unwrap_or_default() })),
+        }
+    }
+}
+
+/// Fails lines over `MAX_LINE_BYTES`, which could be slow to match.
+fn check_line_length(config: &Config, line: &str) -> Result<(), LogError> {
+    if line.len() > config.max_line_bytes {
+        return Err(LogError::LineTooLong(line.len()));
+    }
+    Ok(())
+}
+
+#[test]
+fn max_line_bytes_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let long = format!(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /{}" 200 9947"#, "a".repeat(64 * 1024));
+    assert_eq!(log2json(&Config::default(), &long).unwrap_err().kind(), "LineTooLong");
+
+    let out = transform_data(&Config::default(), format!("{}\n{}", long, line).into_bytes()).unwrap().unwrap();
+    assert_eq!(String::from_utf8(out.data).unwrap().lines().count(), 1);
+    let err = transform_data(&Config::default(), long.clone().into_bytes()).unwrap_err();
+    assert_eq!(err.error.to_string(), format!("line is {} bytes, over MAX_LINE_BYTES", long.len()));
+
+    let config = Config::from_vars(|key| if key == "MAX_LINE_BYTES" { Some("32".to_string()) } else { None }).unwrap();
+    assert_eq!(log2json(&config, line).unwrap_err().kind(), "LineTooLong");
+    let alb = "http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188";
+    assert_eq!(alb::parse_alb_log(&config, alb).unwrap_err().kind(), "LineTooLong");
+}
+
+/// Strips the spaces and line terminators around a line, such as the `\r`
+/// left by CRLF files.
+fn trim_line(line: &str) -> &str {
+    line.trim_matches(&[' ', '\r', '\n'][..])
+}
+
+#[test]
+fn transform_data_trim_test() {
+    let data = concat!(
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#, "\r\n",
+        r#"   7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search HTTP/1.1" 404 10 "-" "curl/7.58.0" "#, "\r\n",
+    );
+    let out = transform_data(&Config::default(), data.as_bytes().to_vec()).unwrap().unwrap();
+    let lines: Vec<serde_json::Value> = String::from_utf8(out.data).unwrap()
+        .split('\n')
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["bytes"], 9947);
+    assert_eq!(lines[1]["host"], "7.248.7.120");
+    assert_eq!(lines[1]["user_agent"], "curl/7.58.0");
+}
+
+/// Rough size of a line's JSON output relative to the line itself, used to
+/// size the output buffer up front.
+static OUTPUT_SIZE_RATIO: usize = 3;
+
+/// The NDJSON output of a record, along with the partition keys derived from
+/// its first line.
+#[derive(Debug)]
+pub struct Transformed {
+    pub data: Vec<u8>,
+    pub partition_keys: BTreeMap<String, String>,
+}
+
+/// Keys for Firehose dynamic partitioning: the UTC date of the line and its
+/// status class.
+fn partition_keys(log: &AccessLog) -> BTreeMap<String, String> {
+    partition_keys_at(&log.time.with_timezone(&Utc), Some(log.response))
+}
+
+/// `partition_keys` for parsers without an `AccessLog`, leaving out
+/// `status_class` when there is no status.
+fn partition_keys_at(time: &DateTime<Utc>, status: Option<u16>) -> BTreeMap<String, String> {
+    let mut keys = BTreeMap::new();
+    keys.insert("year".to_string(), format!("{:04}", time.year()));
+    keys.insert("month".to_string(), format!("{:02}", time.month()));
+    keys.insert("day".to_string(), format!("{:02}", time.day()));
+    if let Some(status) = status {
+        keys.insert("status_class".to_string(), status_class(status).to_string());
+    }
+    keys
+}
+
+/// `2xx`, `4xx` and so on, or `other` outside the `1xx`-`5xx` range.
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "other",
+    }
+}
+
+#[test]
+fn status_class_test() {
+    let line = |status| format!(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" {} 9947"#, status);
+    for &(status, class) in &[(200, "2xx"), (301, "3xx"), (404, "4xx"), (503, "5xx")] {
+        assert_eq!(log2json(&Config::default(), &line(status)).unwrap()["status_class"], class);
+    }
+    assert_eq!(status_class(101), "1xx");
+    assert_eq!(status_class(600), "other");
+}
+
+#[test]
+fn partition_keys_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let log = parse_access_log(&Config::default(), data).unwrap();
+    let keys = partition_keys(&log);
+
+    assert_eq!(keys["year"], "2017");
+    assert_eq!(keys["month"], "12");
+    assert_eq!(keys["day"], "14");
+    assert_eq!(keys["status_class"], "2xx");
+
+    let data = r#"7.248.7.119 - - [01/Jan/2018:08:00:00 +09:00] "GET /explore" 503 10"#;
+    let keys = partition_keys(&parse_access_log(&Config::default(), data).unwrap());
+    assert_eq!(keys["year"], "2017");
+    assert_eq!(keys["month"], "12");
+    assert_eq!(keys["day"], "31");
+    assert_eq!(keys["status_class"], "5xx");
+}
+
+/// Decodes a payload as `TEXT_ENCODING` says. Under `latin1` only payloads
+/// that aren't valid UTF-8 are taken for Windows-1252, the superset of
+/// Latin-1 that browsers use, so UTF-8 ones keep working.
+fn decode_text(config: &Config, data: Vec<u8>) -> Result<String, LogError> {
+    match config.text_encoding {
+        TextEncoding::Utf8 => Ok(String::from_utf8(data)?),
+        TextEncoding::Latin1 => String::from_utf8(data)
+            .or_else(|e| Ok(encoding_rs::WINDOWS_1252.decode_without_bom_handling(e.as_bytes()).0.into_owned())),
+        TextEncoding::Lossy => Ok(String::from_utf8(data).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())),
+    }
+}
+
+#[test]
+fn text_encoding_test() {
+    let data = b"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] \"GET /caf\xe9 HTTP/1.1\" 200 9947".to_vec();
+    let encoding = |x: &str| Config::from_vars(|key| if key == "TEXT_ENCODING" { Some(x.to_string()) } else { None }).unwrap();
+    let path = |config: &Config, data: &[u8]| {
+        let out = transform_data(config, data.to_vec()).unwrap().unwrap();
+        serde_json::from_slice::<serde_json::Value>(&out.data).unwrap()["path"].clone()
+    };
+
+    assert_eq!(transform_data(&Config::default(), data.clone()).unwrap_err().error.kind(), "UTF8Error");
+    assert_eq!(path(&encoding("latin1"), &data), "/caf\u{e9}");
+    assert_eq!(path(&encoding("lossy"), &data), "/caf\u{fffd}");
+    let utf8 = String::from_utf8_lossy(&data).replace('\u{fffd}', "\u{e9}").into_bytes();
+    assert_eq!(path(&encoding("latin1"), &utf8), "/caf\u{e9}");
+    assert!(Config::from_vars(|key| if key == "TEXT_ENCODING" { Some("ebcdic".to_string()) } else { None }).is_err());
+}
+
+static GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+static ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// The stream identifier chunk that opens every framed Snappy stream.
+static SNAPPY_MAGIC: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+
+/// Inflates gzip payloads and, with the `compression` feature, Zstandard
+/// and framed Snappy ones, each detected by their magic bytes. Raw Snappy
+/// has none, so under `SNAPPY_FRAMING=raw` anything else is tried as raw
+/// Snappy and kept as it is if that fails. Anything else is returned
+/// untouched.
+fn decompress(config: &Config, data: Vec<u8>) -> Result<Vec<u8>, LogError> {
+    let mut out = Vec::new();
+    if data.starts_with(GZIP_MAGIC) {
+        MultiGzDecoder::new(&data[..]).read_to_end(&mut out)?;
+    } else if data.starts_with(ZSTD_MAGIC) {
+        decompress_zstd(&data, &mut out)?;
+    } else if data.starts_with(SNAPPY_MAGIC) {
+        decompress_snappy(&data, &mut out)?;
+    } else if config.snappy_framing == SnappyFraming::Raw {
+        return Ok(decompress_raw_snappy(&data).unwrap_or(data));
+    } else {
+        return Ok(data);
+    }
+    Ok(out)
+}
+
+#[cfg(feature = "compression")]
+fn decompress_zstd(data: &[u8], out: &mut Vec<u8>) -> Result<(), LogError> {
+    zstd::stream::Decoder::new(data)?.read_to_end(out)?;
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+fn decompress_snappy(data: &[u8], out: &mut Vec<u8>) -> Result<(), LogError> {
+    snap::read::FrameDecoder::new(data).read_to_end(out)?;
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+fn decompress_raw_snappy(data: &[u8]) -> Option<Vec<u8>> {
+    snap::raw::Decoder::new().decompress_vec(data).ok()
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_zstd(_: &[u8], _: &mut Vec<u8>) -> Result<(), LogError> {
+    Err(LogError::FormatError("Zstandard payloads need the compression feature".to_string()))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_snappy(_: &[u8], _: &mut Vec<u8>) -> Result<(), LogError> {
+    Err(LogError::FormatError("Snappy payloads need the compression feature".to_string()))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_raw_snappy(_: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(all(test, feature = "compression"))]
+static COMPRESSED_LINE: &str = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+
+#[test]
+#[cfg(feature = "compression")]
+fn transform_data_zstd_test() {
+    let data = zstd::stream::encode_all(COMPRESSED_LINE.as_bytes(), 0).unwrap();
+    assert!(data.starts_with(ZSTD_MAGIC));
+    let out = transform_data(&Config::default(), data).unwrap().unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&out.data).unwrap()["host"], "7.248.7.119");
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn transform_data_snappy_test() {
+    use std::io::Write;
+
+    let mut encoder = snap::write::FrameEncoder::new(Vec::new());
+    encoder.write_all(COMPRESSED_LINE.as_bytes()).unwrap();
+    let data = encoder.into_inner().unwrap();
+    let out = transform_data(&Config::default(), data).unwrap().unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&out.data).unwrap()["host"], "7.248.7.119");
+
+    let raw = snap::raw::Encoder::new().compress_vec(COMPRESSED_LINE.as_bytes()).unwrap();
+    assert!(transform_data(&Config::default(), raw.clone()).is_err());
+    let config = Config::from_vars(|key| if key == "SNAPPY_FRAMING" { Some("raw".to_string()) } else { None }).unwrap();
+    let out = transform_data(&config, raw).unwrap().unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&out.data).unwrap()["host"], "7.248.7.119");
+    assert!(transform_data(&config, COMPRESSED_LINE.as_bytes().to_vec()).unwrap().is_some());
+}
+
+#[test]
+fn transform_data_ndjson_test() {
+    let data = concat!(
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#, "\n",
+        "garbage\n",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#, "\n",
+    );
+    let out = transform_data(&Config::default(), data.as_bytes().to_vec()).unwrap().unwrap();
+    let lines = String::from_utf8(out.data).unwrap();
+    let lines: Vec<serde_json::Value> = lines.split('\n')
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["host"], "7.248.7.119");
+    assert_eq!(lines[1]["host"], "7.248.7.120");
+}
+
+#[test]
+fn transform_data_auto_flavor_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("auto".to_string()),
+        _ => None,
+    }).unwrap();
+    let data = concat!(
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#, "\n",
+        r#"2001:db8::1 - bob [10/Oct/2000:13:55:36 -0700] "GET /a HTTP/1.1" 200 2326 "-" "curl/7.58.0""#, "\n",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#, "\n",
+    );
+    let out = transform_data(&config, data.as_bytes().to_vec()).unwrap().unwrap();
+    let lines: Vec<serde_json::Value> = String::from_utf8(out.data).unwrap()
+        .split('\n')
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["host"], "7.248.7.119");
+    assert_eq!(lines[1]["host"], "2001:db8::1");
+    assert_eq!(lines[1]["authuser"], "bob");
+    assert_eq!(lines[2]["host"], "7.248.7.120");
+}
+
+#[test]
+fn transform_data_all_lines_invalid_test() {
+    assert!(transform_data(&Config::default(), b"garbage\nmore garbage".to_vec()).is_err());
+}
+
+#[test]
+fn transform_data_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "-" "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1" "#;
+    let a = log2json(&Config::default(), data).unwrap();
+
+    println!("{}", a);
+}
+
+#[test]
+fn nginx_flavor_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FLAVOR" => Some("nginx".to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"203.0.113.5 - alice [10/Oct/2000:13:55:36 -0700] "GET /index.html HTTP/1.1" 200 2326 "http://example.com/" "curl/7.58.0""#;
+    let a = log2json(&config, data).unwrap();
+
+    assert_eq!(a["host"], "203.0.113.5");
+    assert!(a["ident"].is_null());
+    assert_eq!(a["authuser"], "alice");
+    assert_eq!(a["@timestamp"], "2000-10-10T13:55:36-07:00");
+    assert_eq!(a["referer"], "http://example.com/");
+    assert_eq!(a["user_agent"], "curl/7.58.0");
+}
+
+#[test]
+fn timestamp_format_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let with_format = |format: &str| {
+        let format = format.to_string();
+        Config::from_vars(move |key| match key {
+            "TIMESTAMP_FORMAT" => Some(format.clone()),
+            _ => None,
+        }).unwrap()
+    };
+
+    let a = log2json(&with_format("rfc3339"), data).unwrap();
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+
+    let a = log2json(&with_format("epoch_millis"), data).unwrap();
+    assert_eq!(a["@timestamp"], 1_513_257_405_000i64);
+    assert_eq!(a["@timestamp_utc"], 1_513_257_405_000i64);
+
+    let a = log2json(&with_format("epoch_seconds"), data).unwrap();
+    assert_eq!(a["@timestamp"], 1_513_257_405i64);
+    assert_eq!(a["@timestamp_utc"], 1_513_257_405i64);
+
+    assert!(Config::from_vars(|key| match key {
+        "TIMESTAMP_FORMAT" => Some("iso".to_string()),
+        _ => None,
+    }).is_err());
+}
+
+#[test]
+fn custom_time_format_test() {
+    let config = |format: &'static str, tz: Option<&'static str>| Config::from_vars(move |key| match key {
+        "LOG_FORMAT" => Some(format.to_string()),
+        "DEFAULT_TZ" => tz.map(|x| x.to_string()),
+        _ => None,
+    }).unwrap();
+
+    let iso = config(r#"%h %l %u %{%Y-%m-%dT%H:%M:%S%:z}t \"%r\" %>s %b"#, None);
+    let a = log2json(&iso, r#"7.248.7.119 - - 2017-12-14T22:16:45+09:00 "GET /explore" 200 9947"#).unwrap();
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+
+    let data = r#"7.248.7.119 - - [2017-12-14 22:16:45] "GET /explore" 200 9947"#;
+    let local = r#"%h %l %u [%{%Y-%m-%d %H:%M:%S}t] \"%r\" %>s %b"#;
+    assert!(log2json(&config(local, None), data).is_err());
+    let a = log2json(&config(local, Some("Asia/Tokyo")), data).unwrap();
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+}
+
+#[test]
+fn default_tz_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45] "GET /explore" 200 9947"#;
+    assert!(log2json(&Config::default(), data).is_err());
+
+    let config = Config::from_vars(|key| match key {
+        "DEFAULT_TZ" => Some("Asia/Tokyo".to_string()),
+        _ => None,
+    }).unwrap();
+    let a = log2json(&config, data).unwrap();
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +01:00] "GET /explore" 200 9947"#;
+    let a = log2json(&config, data).unwrap();
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+01:00");
+}
+
+#[test]
+fn geo_omitted_without_database_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let a = log2json(&Config::default(), data).unwrap();
+
+    assert!(a.get("geo").is_none());
+}
+
+#[test]
+fn dash_as_null_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 304 -"#;
+    let a = log2json(&Config::default(), data).unwrap();
+
+    assert!(a["ident"].is_null());
+    assert!(a["authuser"].is_null());
+    assert!(a["bytes"].is_null());
+    assert_eq!(a["response"], 304);
+}
+
+#[test]
+fn large_bytes_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /video.mp4" 200 5368709120"#;
+    let a = log2json(&Config::default(), data).unwrap();
+
+    assert_eq!(a["bytes"], 5_368_709_120u64);
+}
+
+#[test]
+fn invalid_status_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 9999 9947"#;
+    assert!(log2json(&Config::default(), data).is_err());
+
+    let config = Config::from_vars(|key| match key {
+        "LOG_PATTERN" => Some(r#"^(?P<host>\S+) \[(?P<time>.+?)\] "(?P<request>.+?)" (?P<status>\d+)"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"7.248.7.119 [14/Dec/2017:22:16:45 +09:00] "GET /explore" 70000"#;
+    assert!(log2json(&config, data).is_err());
+}
+
+#[test]
+fn status_range_test() {
+    let line = |status| format!(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" {} 9947"#, status);
+
+    match log2json(&Config::default(), &line("099")) {
+        Err(LogError::InvalidStatus(99)) => {}
+        x => panic!("unexpected {:?}", x),
+    }
+    match log2json(&Config::default(), &line("600")) {
+        Err(LogError::InvalidStatus(600)) => {}
+        x => panic!("unexpected {:?}", x),
+    }
+    assert_eq!(log2json(&Config::default(), &line("200")).unwrap()["response"], 200);
+}
+
+#[test]
+fn field_map_test() {
+    let config = Config::from_vars(|key| match key {
+        "FIELD_MAP" => Some(r#"{"@timestamp": "event_time"}"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let a = log2json(&config, data).unwrap();
+
+    assert_eq!(a["event_time"], "2017-12-14T22:16:45+09:00");
+    assert!(a.get("@timestamp").is_none());
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+}
+
+#[test]
+fn retain_raw_test() {
+    let config = Config::from_vars(|key| match key {
+        "RETAIN_RAW" => Some("1".to_string()),
+        _ => None,
+    }).unwrap();
+    let line = "  7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] \"GET /explore?q=\\\"x\\\"\" 200 9947 \r";
+    let out = transform_data(&config, line.as_bytes().to_vec()).unwrap().unwrap();
+    let a: serde_json::Value = serde_json::from_slice(&out.data).unwrap();
+    assert_eq!(a["raw"], line);
+
+    assert!(log2json(&Config::default(), line).unwrap().get("raw").is_none());
+}
+
+#[test]
+fn timestamp_mode_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let json = |mode: Option<&str>, ts_field: Option<&str>| {
+        let config = Config::from_vars(|key| match key {
+            "TIMESTAMP_MODE" => mode.map(|x| x.to_string()),
+            "TS_FIELD" => ts_field.map(|x| x.to_string()),
+            "TS_UTC_FIELD" => ts_field.map(|_| "ts_utc".to_string()),
+            _ => None,
+        }).unwrap();
+        log2json(&config, data).unwrap()
+    };
+
+    for mode in &[None, Some("both")] {
+        let a = json(*mode, None);
+        assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+        assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+    }
+
+    let a = json(Some("local_only"), None);
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert!(a.get("@timestamp_utc").is_none());
+
+    let a = json(Some("UTC_ONLY"), None);
+    assert!(a.get("@timestamp").is_none());
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+
+    let a = json(Some("utc_only"), Some("ts"));
+    assert!(a.get("ts").is_none());
+    assert_eq!(a["ts_utc"], "2017-12-14T13:16:45+00:00");
+
+    let a = json(Some("local_only"), Some("ts"));
+    assert_eq!(a["ts"], "2017-12-14T22:16:45+09:00");
+    assert!(a.get("ts_utc").is_none());
+
+    let config = Config::from_vars(|key| match key {
+        "TIMESTAMP_MODE" => Some("utc_only".to_string()),
+        "OUTPUT_SCHEMA" => Some("ecs".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(log2json(&config, data).unwrap()["@timestamp"], "2017-12-14T22:16:45+09:00");
+
+    assert!(Config::from_vars(|key| match key {
+        "TIMESTAMP_MODE" => Some("neither".to_string()),
+        _ => None,
+    }).is_err());
+}
+
+#[test]
+fn timestamp_field_names_test() {
+    let config = Config::from_vars(|key| match key {
+        "TS_FIELD" => Some("ts".to_string()),
+        "TS_UTC_FIELD" => Some("ts_utc".to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let a = log2json(&config, data).unwrap();
+
+    assert_eq!(a["ts"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["ts_utc"], "2017-12-14T13:16:45+00:00");
+    assert!(a.get("@timestamp").is_none());
+    assert!(a.get("@timestamp_utc").is_none());
+}
+
+#[test]
+fn custom_log_pattern_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_PATTERN" => Some(r#"^(?P<host>[\d.]+)\|(?P<ident>\S+)\|(?P<authuser>\S+)\|\[(?P<time>.+?)\]\|"(?P<request>.+?)"\|(?P<status>\d{3})\|(?P<bytes>\d+)"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"7.248.7.119|-|-|[14/Dec/2017:22:16:45 +09:00]|"GET /explore"|200|9947"#;
+    let a = log2json(&config, data).unwrap();
+
+    assert_eq!(a["host"], "7.248.7.119");
+    assert_eq!(a["response"], 200);
+    assert_eq!(a["bytes"], 9947);
+}
+
+#[test]
+fn log_format_directive_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some(r#"%h %l %u %t \"%r\" %>s %O \"%{User-Agent}i\""#.to_string()),
+        _ => None,
+    }).unwrap();
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 "curl/7.58.0""#;
+    let a = log2json(&config, data).unwrap();
+
+    assert_eq!(a["protocol"], "HTTP/1.1");
+    assert_eq!(a["user_agent"], "curl/7.58.0");
+    assert!(a["referer"].is_null());
+    assert_eq!(a["bytes"], 9947);
+    assert_eq!(a["bytes_sent"], 9947);
+    assert!(a.get("bytes_received").is_none());
+}
+
+#[test]
+fn tab_separated_test() {
+    let config = Config::from_vars(|key| match key {
+        "FIELD_SEPARATOR" => Some("tab".to_string()),
+        _ => None,
+    }).unwrap();
+    let a = log2json(&config, "7.248.7.119\t-\tfrank smith\t[14/Dec/2017:22:16:45 +09:00]\tGET /explore?q=a b HTTP/1.1\t200\t9947\thttp://example.com/\tMozilla/5.0 (X11; Linux x86_64)").unwrap();
+    assert_eq!(a["authuser"], "frank smith");
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["path"], "/explore?q=a b");
+    assert_eq!(a["protocol"], "HTTP/1.1");
+    assert_eq!(a["response"], 200);
+    assert_eq!(a["referer"], "http://example.com/");
+    assert_eq!(a["user_agent"], "Mozilla/5.0 (X11; Linux x86_64)");
+
+    let a = log2json(&config, "7.248.7.119\t-\t-\t14/Dec/2017:22:16:45 +09:00\tGET /\t404\t-").unwrap();
+    assert_eq!(a["response"], 404);
+    assert!(a["user_agent"].is_null());
+
+    assert!(Config::from_vars(|key| if key == "FIELD_SEPARATOR" { Some("comma".to_string()) } else { None }).is_err());
+}
+
+#[test]
+fn log_format_bytes_received_sent_test() {
+    let config = Config::from_vars(|key| match key {
+        "LOG_FORMAT" => Some(r#"%h %l %u %t \"%r\" %>s %b %I %O"#.to_string()),
+        _ => None,
+    }).unwrap();
+    let a = log2json(&config, r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 482 10311"#).unwrap();
+    assert_eq!(a["bytes"], 9947);
+    assert_eq!(a["bytes_received"], 482);
+    assert_eq!(a["bytes_sent"], 10311);
+
+    let a = log2json(&config, r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 304 - - -"#).unwrap();
+    assert!(a["bytes"].is_null());
+    assert_eq!(a["bytes_received"], 0);
+    assert_eq!(a["bytes_sent"], 0);
+
+    assert!(log2json(&Config::default(), r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#)
+        .unwrap().get("bytes_sent").is_none());
+}
+
+#[test]
+fn combined_log_format_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "-" "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1" "#;
+    let a = log2json(&Config::default(), data).unwrap();
+
+    assert!(a["referer"].is_null());
+    assert_eq!(a["user_agent"], "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1");
+    assert_eq!(a["ua"]["browser"], "Firefox");
+    assert_eq!(a["ua"]["category"], "pc");
+}
+
+#[test]
+fn common_log_format_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let a = log2json(&Config::default(), data).unwrap();
+
+    assert!(a["referer"].is_null());
+    assert!(a["user_agent"].is_null());
+    assert!(a.get("ua").is_none());
+}
+
+/// Base64-decodes a record payload and runs it through `transform_data`.
+fn decode_and_transform(config: &Config, data: &str) -> Result<Option<Transformed>, RecordError> {
+    let data = decode_base64(data)?;
+    transform_data(config, data)
+}
+
+/// Decodes standard base64, falling back to the URL-safe alphabet, unpadded
+/// or padded, which some producers use. Fails with the standard decoder's
+/// error.
+fn decode_base64(data: &str) -> Result<Vec<u8>, LogError> {
+    let data = data.as_bytes();
+    BASE64.decode(data)
+        .or_else(|e| BASE64URL_NOPAD.decode(data).or_else(|_| BASE64URL.decode(data)).map_err(|_| e))
+        .map_err(LogError::from)
+}
+
+#[test]
+fn decode_base64_test() {
+    assert_eq!(decode_base64("Pz8_").unwrap(), b"???");
+    assert_eq!(decode_base64("Pz8/").unwrap(), b"???");
+    assert_eq!(decode_base64("Pz-_Pw").unwrap(), decode_base64("Pz+/Pw==").unwrap());
+    assert_eq!(decode_base64("Pz-_Pw==").unwrap(), decode_base64("Pz+/Pw==").unwrap());
+    assert_eq!(decode_base64("!!!!").unwrap_err().kind(), "EncodingError");
+}
+
+/// Whether `record_id` falls within `SAMPLE_RATE`. Decided by an FNV-1a
+/// hash of the ID rather than at random, so a retried record gets the same
+/// answer; the hash is mixed with MurmurHash3's finalizer since FNV alone
+/// leaves the high bits of short, similar IDs clustered.
+fn sampled(config: &Config, record_id: &str) -> bool {
+    if config.sample_rate >= 1.0 {
+        return true;
+    }
+    let mut hash = record_id.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ u64::from(b)).wrapping_mul(0x100000001b3));
+    hash = (hash ^ (hash >> 33)).wrapping_mul(0xff51afd7ed558ccd);
+    hash = (hash ^ (hash >> 33)).wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    (hash as f64 / u64::MAX as f64) < config.sample_rate
+}
+
+#[test]
+fn sampled_test() {
+    let rate = |x: &str| Config::from_vars(|key| if key == "SAMPLE_RATE" { Some(x.to_string()) } else { None });
+    assert!((0..100).all(|i| sampled(&Config::default(), &i.to_string())));
+    assert!((0..100).all(|i| !sampled(&rate("0").unwrap(), &i.to_string())));
+    assert_eq!(rate("1.5").err().unwrap().to_string(), "invalid log format: SAMPLE_RATE must be between 0 and 1");
+}
+
+/// The fields `INCLUDE_ARRIVAL_TS`, `INCLUDE_INVOCATION_ID` and
+/// `RECORD_ID_PREFIX` add to each of a record's output lines, from an
+/// arrival time in epoch milliseconds.
+fn record_fields(config: &Config, invocation_id: &str, record: &FirehoseRecord) -> Result<serde_json::Map<String, serde_json::Value>, LogError> {
+    let mut fields = serde_json::Map::new();
+    if config.include_arrival_ts {
+        let arrival = record.approximate_arrival_timestamp;
+        let time = DateTime::from_timestamp_millis(arrival.round() as i64)
+            .ok_or_else(|| LogError::FormatError(format!("arrival time {} out of range", arrival)))?;
+        fields.insert("firehose_arrival_time".to_string(), serde_json::Value::from(time.to_rfc3339()));
+    }
+    if config.include_invocation_id {
+        fields.insert("invocation_id".to_string(), serde_json::Value::from(invocation_id));
+    }
+    fields.extend(stream_fields(config));
+    Ok(fields)
+}
+
+/// The fields `RECORD_ID_PREFIX` adds to every output line, whichever
+/// handler wrote it.
+fn stream_fields(config: &Config) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields = serde_json::Map::new();
+    if let Some(ref source_stream) = config.source_stream {
+        fields.insert("source_stream".to_string(), serde_json::Value::from(source_stream.as_str()));
+    }
+    fields
+}
+
+/// Adds `fields` at the start of each output line of `OUTPUT_FORMAT=json`;
+/// `Config` rejects them for CSV, whose rows have no room for them. Lines are spliced rather than parsed
+/// again, keeping their field order; those that aren't objects, as under an
+/// `OUTPUT_ENVELOPE` of another shape, are left alone. A field the line
+/// already has, as passed-through JSON may, keeps the line's value.
+fn prepend_fields(data: &[u8], fields: &serde_json::Map<String, serde_json::Value>) -> Result<Vec<u8>, LogError> {
+    let head = object_head(fields)?;
+    let quoted: Vec<String> = fields.keys().map(|x| serde_json::Value::from(x.as_str()).to_string()).collect();
+
+    let mut out = Vec::with_capacity(data.len() + head.len() * 4);
+    for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            out.push(b'\n');
+        }
+        let rest = match line.strip_prefix(b"{") {
+            Some(rest) => rest,
+            None => {
+                out.extend_from_slice(line);
+                continue;
+            }
+        };
+
+        // Only lines that mention one of the keys are parsed to find out.
+        let own_head;
+        let head = if quoted.iter().any(|x| line.windows(x.len()).any(|w| w == x.as_bytes())) {
+            let keys: BTreeMap<String, serde::de::IgnoredAny> = serde_json::from_slice(line).unwrap_or_default();
+            let fields = fields.iter()
+                .filter(|&(k, _)| !keys.contains_key(k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            own_head = object_head(&fields)?;
+            &own_head
+        } else {
+            &head
+        };
+        out.extend_from_slice(head);
+        if head.len() > 1 && !rest.starts_with(b"}") {
+            out.push(b',');
+        }
+        out.extend_from_slice(rest);
+    }
+    Ok(out)
+}
+
+/// `fields` as an object without its closing brace.
+fn object_head(fields: &serde_json::Map<String, serde_json::Value>) -> Result<Vec<u8>, LogError> {
+    let mut head = serde_json::to_vec(fields)?;
+    head.pop();
+    Ok(head)
+}
+
+#[test]
+fn prepend_fields_test() {
+    let mut fields = serde_json::Map::new();
+    fields.insert("firehose_arrival_time".to_string(), serde_json::Value::from("2017-12-14T13:16:45.250+00:00"));
+    fields.insert("invocation_id".to_string(), serde_json::Value::from("invocation"));
+    let out = prepend_fields(b"{\"a\":1}\n{}\n[1]", &fields).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), concat!(
+        "{\"firehose_arrival_time\":\"2017-12-14T13:16:45.250+00:00\",\"invocation_id\":\"invocation\",\"a\":1}\n",
+        "{\"firehose_arrival_time\":\"2017-12-14T13:16:45.250+00:00\",\"invocation_id\":\"invocation\"}\n",
+        "[1]",
+    ));
+
+    let out = prepend_fields(b"{\"invocation_id\":\"mine\",\"a\":1}\n{\"a\":\"invocation_id\"}", &fields).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), concat!(
+        "{\"firehose_arrival_time\":\"2017-12-14T13:16:45.250+00:00\",\"invocation_id\":\"mine\",\"a\":1}\n",
+        "{\"firehose_arrival_time\":\"2017-12-14T13:16:45.250+00:00\",\"invocation_id\":\"invocation\",\"a\":\"invocation_id\"}",
+    ));
+    let out = prepend_fields(b"{\"invocation_id\":\"mine\",\"firehose_arrival_time\":0}", &fields).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "{\"invocation_id\":\"mine\",\"firehose_arrival_time\":0}");
+}
+
+/// Transforms one record of the invocation `invocation_id`.
+fn transform_record(config: &Config, invocation_id: &str, record: &FirehoseRecord) -> TransformationRecord {
+    if !sampled(config, &record.record_id) {
+        return TransformationRecord {
+            record_id: record.record_id.to_string(),
+            data: record.data.to_string(),
+            result: DROPPED,
+            error_detail: None,
+            metadata: None,
+        };
+    }
+
+    decode_and_transform(config, &record.data)
+        .and_then(|x| match x {
+            Some(mut x) => {
+                let fields = record_fields(config, invocation_id, record)?;
+                if !fields.is_empty() && config.output_format == OutputFormat::Json {
+                    x.data = prepend_fields(&x.data, &fields)?;
+                }
+                let data = BASE64.encode(&x.data);
+                if data.len() > config.max_record_bytes {
+                    return Err(LogError::RecordTooLarge(data.len()).into());
+                }
+                Ok(TransformationRecord {
+                    record_id: record.record_id.to_string(),
+                    data,
+                    result: OK,
+                    error_detail: None,
+                    metadata: if config.partition_keys {
+                        Some(Metadata { partition_keys: x.partition_keys })
+                    } else {
+                        None
+                    },
+                })
+            }
+            None => Ok(TransformationRecord {
+                record_id: record.record_id.to_string(),
+                data: record.data.to_string(),
+                result: DROPPED,
+                error_detail: None,
+                metadata: None,
+            }),
+        })
+        .unwrap_or_else(|e| {
+            if config.passthrough_on_parse_error {
+                tracing::info!(record_id = %record.record_id, error_type = e.error.kind(), "record passed through: {}", e.error);
+                return TransformationRecord {
+                    record_id: record.record_id.to_string(),
+                    data: record.data.to_string(),
+                    result: OK,
+                    error_detail: None,
+                    metadata: None,
+                };
+            }
+
+            tracing::warn!(record_id = %record.record_id, error_type = e.error.kind(), "record failed: {}", e.error);
+            TransformationRecord {
+                record_id: record.record_id.to_string(),
+                data: error_payload(record, &e.error),
+                result: NG,
+                error_detail: Some(ErrorDetail {
+                    error_type: e.error.kind(),
+                    line: e.line.map(|x| truncate(&x, MAX_ERROR_LINE_BYTES).to_string()),
+                }),
+                metadata: None,
+            }
+        })
+}
+
+static MAX_ERROR_LINE_BYTES: usize = 1024;
+
+/// How much of an unmatched line `RegexParseError` keeps, enough to spot a
+/// misconfigured format in the logs.
+static MAX_UNMATCHED_BYTES: usize = 256;
+
+/// Cuts `s` down to at most `max` bytes without splitting a character.
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[test]
+fn truncate_test() {
+    assert_eq!(truncate("abc", 5), "abc");
+    assert_eq!(truncate("abcdef", 3), "abc");
+    assert_eq!(truncate("aあ", 2), "a");
+}
+
+/// Base64-encoded `{"error": ..., "raw": ...}` object explaining why `record`
+/// failed, so the reason is visible in the Firehose error output.
+fn error_payload(record: &FirehoseRecord, err: &LogError) -> String {
+    let raw = decode_base64(&record.data)
+        .map(|x| String::from_utf8_lossy(&x).into_owned())
+        .unwrap_or_else(|_| record.data.to_string());
+
+    let payload = serde_json::json!({
+        "error": err.to_string(),
+        "raw": raw,
+    });
+    BASE64.encode(payload.to_string().as_bytes())
+}
+
+#[cfg(test)]
+fn test_record(record_id: &str, data: &str) -> FirehoseRecord {
+    FirehoseRecord {
+        record_id: record_id.to_string(),
+        data: BASE64.encode(data.as_bytes()),
+        approximate_arrival_timestamp: 1513257405453.0,
+    }
+}
+
+#[test]
+fn transform_record_arrival_time_test() {
+    let record = test_record("1", r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#);
+    let config = Config::from_vars(|key| if key == "INCLUDE_ARRIVAL_TS" { Some("true".to_string()) } else { None }).unwrap();
+    let r = transform_record(&config, "invocation", &record);
+
+    assert_eq!(r.result, OK);
+    let line: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(line["firehose_arrival_time"], "2017-12-14T13:16:45.453+00:00");
+    assert_eq!(line["host"], "7.248.7.119");
+
+    let r = transform_record(&Config::default(), "invocation", &record);
+    let line: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(line.get("firehose_arrival_time"), None);
+}
+
+#[test]
+fn transform_record_ok_test() {
+    let record = test_record("1", r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#);
+    let r = transform_record(&Config::default(), "invocation", &record);
+
+    assert_eq!(r.result, OK);
+    assert_eq!(r.error_detail, None);
+    assert!(serde_json::to_value(&r).unwrap().get("error_detail").is_none());
+    let data: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(data["host"], "7.248.7.119");
+}
+
+#[test]
+fn transform_record_error_payload_test() {
+    let record = test_record("1", "garbage");
+    let r = transform_record(&Config::default(), "invocation", &record);
+
+    assert_eq!(r.result, NG);
+    let data: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(data, serde_json::json!({"error": "FAIL. unmatched pattern: garbage", "raw": "garbage"}));
+    assert_eq!(r.error_detail, Some(ErrorDetail {
+        error_type: "RegexParseError",
+        line: Some("garbage".to_string()),
+    }));
+}
+
+#[test]
+fn error_detail_truncated_test() {
+    let line = "x".repeat(4096);
+    let r = transform_record(&Config::default(), "invocation", &test_record("1", &line));
+
+    let detail = r.error_detail.unwrap();
+    assert_eq!(detail.error_type, "RegexParseError");
+    assert_eq!(detail.line.unwrap().len(), MAX_ERROR_LINE_BYTES);
+}
+
+#[test]
+fn transform_record_error_log_test() {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || transform_record(&Config::default(), "invocation", &test_record("42", "garbage")));
+
+    let output = buffer.0.lock().unwrap();
+    let event: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(event["level"], "WARN");
+    assert_eq!(event["record_id"], "42");
+    assert_eq!(event["error_type"], "RegexParseError");
+    assert_eq!(event["message"], "record failed: FAIL. unmatched pattern: garbage");
+}
+
+#[test]
+fn transform_record_empty_data_test() {
+    for data in &["", "ICAK"] {
+        let record = FirehoseRecord {
+            record_id: "1".to_string(),
+            data: data.to_string(),
+            approximate_arrival_timestamp: 0.0,
+        };
+        let r = transform_record(&Config::default(), "invocation", &record);
+        assert_eq!(r.result, NG);
+        assert_eq!(r.error_detail.unwrap().error_type, "EmptyRecord");
+    }
+}
+
+#[test]
+fn transform_record_too_large_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let record = test_record("1", &vec![line; 100].join("\n"));
+    let config = Config::from_vars(|key| match key {
+        "MAX_RECORD_BYTES" => Some("4096".to_string()),
+        _ => None,
+    }).unwrap();
+
+    let r = transform_record(&config, "invocation", &record);
+    assert_eq!(r.result, NG);
+    assert_eq!(r.error_detail.unwrap().error_type, "RecordTooLarge");
+    let data: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert!(data["error"].as_str().unwrap().ends_with("over MAX_RECORD_BYTES"));
+
+    assert_eq!(transform_record(&Config::default(), "invocation", &record).result, OK);
+}
+
+#[test]
+fn transform_record_base64url_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore?a=>>>" 200 9947"#;
+    let record = FirehoseRecord {
+        record_id: "1".to_string(),
+        data: BASE64URL_NOPAD.encode(data.as_bytes()),
+        approximate_arrival_timestamp: 0.0,
+    };
+    assert!(record.data.contains('-') || record.data.contains('_'));
+
+    let r = transform_record(&Config::default(), "invocation", &record);
+    assert_eq!(r.result, OK);
+    let out: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(out["query"]["a"], ">>>");
+}
+
+#[test]
+fn transform_record_passthrough_test() {
+    let record = test_record("heartbeat", "ping");
+
+    let r = transform_record(&Config::default(), "invocation", &record);
+    assert_eq!(r.result, NG);
+
+    let config = Config::from_vars(|key| match key {
+        "PASSTHROUGH_ON_PARSE_ERROR" => Some("true".to_string()),
+        _ => None,
+    }).unwrap();
+    let r = transform_record(&config, "invocation", &record);
+    assert_eq!(r.result, OK);
+    assert_eq!(r.data, record.data);
+    assert_eq!(r.error_detail, None);
+}
+
+#[test]
+fn transform_record_partition_keys_test() {
+    let record = test_record("1", r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 404 9947"#);
+    assert!(transform_record(&Config::default(), "invocation", &record).metadata.is_none());
+
+    let config = Config::from_vars(|key| match key {
+        "PARTITION_KEYS" => Some("true".to_string()),
+        _ => None,
+    }).unwrap();
+    let r = serde_json::to_value(transform_record(&config, "invocation", &record)).unwrap();
+    assert_eq!(r["metadata"]["partitionKeys"], serde_json::json!({
+        "year": "2017",
+        "month": "12",
+        "day": "14",
+        "status_class": "4xx",
+    }));
+}
+
+#[test]
+fn transform_record_drop_path_test() {
+    let line = |path| format!(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET {} HTTP/1.1" 200 100"#, path);
+    let config = Config::from_vars(|key| match key {
+        "DROP_PATH_REGEX" => Some(r"\.(css|js|png)$".to_string()),
+        _ => None,
+    }).unwrap();
+
+    let r = transform_record(&config, "invocation", &test_record("1", &line("/img/logo.png?v=3")));
+    assert_eq!(r.result, DROPPED);
+    assert_eq!(transform_record(&config, "invocation", &test_record("2", &line("/api/users/42"))).result, OK);
+
+    let r = transform_record(&config, "invocation", &test_record("3", &[line("/app.js"), line("/api/orders")].join("\n")));
+    let data = String::from_utf8(BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(data.lines().count(), 1);
+    assert!(data.contains("/api/orders"));
+
+    assert!(Config::from_vars(|key| if key == "DROP_PATH_REGEX" { Some("(".to_string()) } else { None }).is_err());
+}
+
+#[test]
+fn transform_record_drop_crawlers_test() {
+    let record = test_record("1", r#"66.249.66.1 - - [14/Dec/2017:22:16:45 +09:00] "GET / HTTP/1.1" 200 100 "-" "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)""#);
+    assert_eq!(transform_record(&Config::default(), "invocation", &record).result, OK);
+
+    let config = Config::from_vars(|key| match key {
+        "DROP_CRAWLERS" => Some("true".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(transform_record(&config, "invocation", &record).result, DROPPED);
+}
+
+#[test]
+fn transform_record_gzip_test() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(br#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#).unwrap();
+    let record = FirehoseRecord {
+        record_id: "1".to_string(),
+        data: BASE64.encode(&encoder.finish().unwrap()),
+        approximate_arrival_timestamp: 1513257405453.0,
+    };
+    let r = transform_record(&Config::default(), "invocation", &record);
+
+    assert_eq!(r.result, OK);
+    let data: serde_json::Value = serde_json::from_slice(&BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(data["host"], "7.248.7.119");
+}
+
+#[test]
+fn transform_record_sample_rate_test() {
+    let config = Config::from_vars(|key| match key {
+        "SAMPLE_RATE" => Some("0.5".to_string()),
+        _ => None,
+    }).unwrap();
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let results: Vec<&str> = (0..1000).map(|i| transform_record(&config, "invocation", &test_record(&i.to_string(), line)).result).collect();
+
+    let dropped = results.iter().filter(|&&x| x == DROPPED).count();
+    assert!(400 < dropped && dropped < 600, "dropped {}", dropped);
+    assert_eq!(results.len() - dropped, results.iter().filter(|&&x| x == OK).count());
+}
+
+#[test]
+fn transform_record_status_filter_test() {
+    let config = Config::from_vars(|key| match key {
+        "STATUS_FILTER" => Some("4xx,5xx".to_string()),
+        _ => None,
+    }).unwrap();
+    let line = |status| format!(r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" {} 9947"#, status);
+
+    assert_eq!(transform_record(&config, "invocation", &test_record("1", &line(200))).result, DROPPED);
+    assert_eq!(transform_record(&config, "invocation", &test_record("2", &line(404))).result, OK);
+    assert_eq!(transform_record(&config, "invocation", &test_record("3", &line(500))).result, OK);
+    assert_eq!(transform_record(&config, "invocation", &test_record("4", "garbage")).result, NG);
+
+    let r = transform_record(&config, "invocation", &test_record("5", &[line(200), line(500), line(404)].join("\n")));
+    let out = String::from_utf8(BASE64.decode(r.data.as_bytes()).unwrap()).unwrap();
+    let statuses: Vec<u64> = out.split('\n').map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()["response"].as_u64().unwrap()).collect();
+    assert_eq!(statuses, vec![500, 404]);
+}
+
+#[test]
+fn transform_record_dropped_test() {
+    let record = test_record("1", r#"10.0.0.1 - - [14/Dec/2017:22:16:45 +09:00] "GET /health HTTP/1.1" 200 0 "-" "ELB-HealthChecker/2.0""#);
+    let r = transform_record(&Config::default(), "invocation", &record);
+
+    assert_eq!(r.result, DROPPED);
+    assert_eq!(r.data, record.data);
+}
+
+/// Transforms every record of a Kinesis invocation, in the order of
+/// `event.records`. Records that fail are logged and reported as `NG`
+/// with no data. Under `FANOUT` a `CSV_HEADER` is left out, as no single
+/// result is the start of a file.
+pub fn transform_kinesis_event(config: &Config, event: &KinesisEvent) -> KinesisResponse {
+    config.reset_last_format();
+    let header = (config.output_format == OutputFormat::Csv && config.csv_header) as usize;
+    let records = config.map_records(&event.records, |x| {
+        let result = |result, data, line| KinesisResult {
+            sequence_number: x.kinesis.sequence_number.to_string(),
+            result,
+            data,
+            line,
+        };
+        match decode_and_transform(config, &x.kinesis.data) {
+            Ok(Some(out)) if config.fanout => String::from_utf8_lossy(&out.data).split('\n')
+                .skip(header)
+                .enumerate()
+                .map(|(i, line)| result(OK, Some(line.to_string()), Some(i)))
+                .collect(),
+            Ok(Some(out)) => vec![result(OK, Some(String::from_utf8_lossy(&out.data).into_owned()), None)],
+            Ok(None) => vec![result(DROPPED, None, None)],
+            Err(e) => {
+                tracing::warn!(sequence_number = %x.kinesis.sequence_number, error_type = e.error.kind(), "record failed: {}", e.error);
+                vec![result(NG, None, None)]
+            }
+        }
+    });
+
+    KinesisResponse { records: records.into_iter().flatten().collect() }
+}
+
+#[cfg(test)]
+static KINESIS_EVENT: &str = r#"{
+  "Records": [
+    {
+      "kinesis": {
+        "kinesisSchemaVersion": "1.0",
+        "partitionKey": "1",
+        "sequenceNumber": "49590338271490256608559692538361571095921575989136588898",
+        "data": "Ny4yNDguNy4xMTkgLSAtIFsxNC9EZWMvMjAxNzoyMjoxNjo0NSArMDk6MDBdICJHRVQgL2V4cGxvcmUiIDIwMCA5OTQ3",
+        "approximateArrivalTimestamp": 1545084650.987
+      },
+      "eventSource": "aws:kinesis",
+      "eventVersion": "1.0",
+      "eventID": "shardId-000000000006:49590338271490256608559692538361571095921575989136588898",
+      "eventName": "aws:kinesis:record",
+      "invokeIdentityArn": "arn:aws:iam::123456789012:role/lambda-role",
+      "awsRegion": "us-east-2",
+      "eventSourceARN": "arn:aws:kinesis:us-east-2:123456789012:stream/lambda-stream"
+    },
+    {
+      "kinesis": {
+        "kinesisSchemaVersion": "1.0",
+        "partitionKey": "1",
+        "sequenceNumber": "49590338271490256608559692540925702759324208523137515618",
+        "data": "Z2FyYmFnZQ==",
+        "approximateArrivalTimestamp": 1545084711.166
+      },
+      "eventSource": "aws:kinesis",
+      "eventVersion": "1.0",
+      "eventID": "shardId-000000000006:49590338271490256608559692540925702759324208523137515618",
+      "eventName": "aws:kinesis:record",
+      "invokeIdentityArn": "arn:aws:iam::123456789012:role/lambda-role",
+      "awsRegion": "us-east-2",
+      "eventSourceARN": "arn:aws:kinesis:us-east-2:123456789012:stream/lambda-stream"
+    }
+  ]
+}"#;
+
+#[test]
+fn kinesis_event_deserialize_test() {
+    let event: KinesisEvent = serde_json::from_str(KINESIS_EVENT).unwrap();
+
+    assert_eq!(event.records.len(), 2);
+    assert_eq!(event.records[0].event_id, "shardId-000000000006:49590338271490256608559692538361571095921575989136588898");
+    assert_eq!(event.records[0].aws_region, "us-east-2");
+    assert_eq!(event.records[0].kinesis.partition_key, "1");
+    assert_eq!(event.records[0].kinesis.sequence_number, "49590338271490256608559692538361571095921575989136588898");
+    assert_eq!(event.records[0].kinesis.approximate_arrival_timestamp, 1545084650.987);
+}
+
+#[test]
+fn transform_kinesis_event_test() {
+    let event: KinesisEvent = serde_json::from_str(KINESIS_EVENT).unwrap();
+    let response = transform_kinesis_event(&Config::default(), &event);
+
+    assert_eq!(response.records.len(), 2);
+    assert_eq!(response.records[0].result, OK);
+    let data: serde_json::Value = serde_json::from_str(response.records[0].data.as_ref().unwrap()).unwrap();
+    assert_eq!(data["host"], "7.248.7.119");
+    assert_eq!(response.records[1].result, NG);
+    assert_eq!(response.records[1].data, None);
+}
+
+#[test]
+fn transform_kinesis_event_fanout_test() {
+    let lines = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        "garbage",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#,
+    ];
+    let mut event: KinesisEvent = serde_json::from_str(KINESIS_EVENT).unwrap();
+    event.records[0].kinesis.data = BASE64.encode(lines.join("\n").as_bytes());
+    let config = Config::from_vars(|key| if key == "FANOUT" { Some("true".to_string()) } else { None }).unwrap();
+    let response = transform_kinesis_event(&config, &event);
+
+    assert_eq!(response.records.len(), 3);
+    for (i, host) in ["7.248.7.119", "7.248.7.120"].iter().enumerate() {
+        let r = &response.records[i];
+        assert_eq!(r.sequence_number, "49590338271490256608559692538361571095921575989136588898");
+        assert_eq!(r.result, OK);
+        assert_eq!(r.line, Some(i));
+        let data: serde_json::Value = serde_json::from_str(r.data.as_ref().unwrap()).unwrap();
+        assert_eq!(data["host"], *host);
+    }
+    assert_eq!(response.records[2].result, NG);
+    assert_eq!(response.records[2].line, None);
+}
+
+#[test]
+fn transform_kinesis_event_fanout_csv_test() {
+    let lines = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#,
+    ];
+    let mut event: KinesisEvent = serde_json::from_str(KINESIS_EVENT).unwrap();
+    event.records.truncate(1);
+    event.records[0].kinesis.data = BASE64.encode(lines.join("\n").as_bytes());
+    let config = Config::from_vars(|key| match key {
+        "FANOUT" | "CSV_HEADER" => Some("true".to_string()),
+        "OUTPUT_FORMAT" => Some("csv".to_string()),
+        _ => None,
+    }).unwrap();
+    let response = transform_kinesis_event(&config, &event);
+
+    assert_eq!(response.records.len(), 2);
+    for (i, host) in ["7.248.7.119", "7.248.7.120"].iter().enumerate() {
+        assert_eq!(response.records[i].line, Some(i));
+        assert!(response.records[i].data.as_ref().unwrap().contains(&format!(",{},", host)));
+    }
+}
+
+/// Transforms every record of a Firehose invocation. The response lists the
+/// records in the order of `event.records`, as Firehose requires, however
+/// many threads they were spread over.
+pub fn transform_event(config: &Config, event: &FirehoseEvent) -> TransformationEvent {
+    config.reset_last_format();
+    let records = config.map_records(&event.records, |x| transform_record(config, &event.invocation_id, x));
+
+    TransformationEvent { records }
+}
+
+#[test]
+fn transform_event_invocation_id_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let event = FirehoseEvent {
+        records: vec![test_record("1", line), test_record("2", &[line, line].join("\n"))],
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "8e5e1f7e-4c5b-4a6b-9b8a-0e0d6f1c2a3b".to_string(),
+    };
+    let config = Config::from_vars(|key| if key == "INCLUDE_INVOCATION_ID" { Some("true".to_string()) } else { None }).unwrap();
+    let result = transform_event(&config, &event);
+
+    let lines: Vec<serde_json::Value> = result.records.iter()
+        .flat_map(|r| String::from_utf8(BASE64.decode(r.data.as_bytes()).unwrap()).unwrap().split('\n').map(|x| serde_json::from_str(x).unwrap()).collect::<Vec<_>>())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines.iter().all(|x| x["invocation_id"] == "8e5e1f7e-4c5b-4a6b-9b8a-0e0d6f1c2a3b" && x["host"] == "7.248.7.119"));
+}
+
+#[test]
+fn transform_event_source_stream_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let event = FirehoseEvent {
+        records: vec![test_record("1", &[line, line].join("\n")), test_record("2", "garbage")],
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+    let config = Config::from_vars(|key| if key == "RECORD_ID_PREFIX" { Some("web-access".to_string()) } else { None }).unwrap();
+    let result = transform_event(&config, &event);
+
+    assert_eq!(result.records[0].record_id, "1");
+    assert_eq!(result.records[1].record_id, "2");
+    let out = String::from_utf8(BASE64.decode(result.records[0].data.as_bytes()).unwrap()).unwrap();
+    for line in out.split('\n') {
+        let a: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(a["source_stream"], "web-access");
+        assert_eq!(a["host"], "7.248.7.119");
+    }
+
+    let out = String::from_utf8(BASE64.decode(transform_event(&Config::default(), &event).records[0].data.as_bytes()).unwrap()).unwrap();
+    assert!(!out.contains("source_stream"));
+}
+
+/// The response to `event` under `DRY_RUN`: each record unchanged and `Ok`,
+/// whatever `transform_event` made of it.
+pub fn dry_run_response(event: &FirehoseEvent) -> TransformationEvent {
+    let records = event.records.iter()
+        .map(|x| TransformationRecord {
+            record_id: x.record_id.to_string(),
+            result: OK,
+            data: x.data.to_string(),
+            error_detail: None,
+            metadata: None,
+        })
+        .collect();
+    TransformationEvent { records }
+}
+
+#[test]
+fn dry_run_response_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let event = FirehoseEvent {
+        records: vec![test_record("1", line), test_record("2", "garbage"), test_record("3", line)],
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+    let config = Config::from_vars(|key| if key == "DRY_RUN" { Some("true".to_string()) } else { None }).unwrap();
+    assert!(config.dry_run);
+
+    let summary = summarize(&transform_event(&config, &event).records);
+    assert_eq!((summary.ok, summary.failed, summary.dropped), (2, 1, 0));
+
+    let response = dry_run_response(&event);
+    assert_eq!(response.records.len(), 3);
+    for (out, record) in response.records.iter().zip(&event.records) {
+        assert_eq!(out.record_id, record.record_id);
+        assert_eq!(out.result, OK);
+        assert_eq!(out.data, record.data);
+        assert_eq!(out.metadata, None);
+    }
+    assert_eq!(summarize(&response.records).ok, 3);
+}
+
+/// Firehose matches the response to the input by position as well as ID, so
+/// the order must survive any parallelism, including uneven work per record.
+#[test]
+fn transform_event_preserves_order_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let ids: Vec<String> = (0..100u64).map(|i| format!("{:016x}", i.wrapping_mul(0x9e3779b97f4a7c15))).collect();
+    let event = FirehoseEvent {
+        records: ids.iter().enumerate()
+            .map(|(i, id)| match i % 3 {
+                0 => test_record(id, &vec![line; 200].join("\n")),
+                1 => test_record(id, "garbage"),
+                _ => test_record(id, line),
+            })
+            .collect(),
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+
+    let levels: &[Option<&str>] = if cfg!(feature = "parallel") { &[Some("1"), Some("2"), Some("8"), None] } else { &[Some("1"), None] };
+    for &parallelism in levels {
+        let config = Config::from_vars(|key| match key {
+            "PARALLELISM" => parallelism.map(|x| x.to_string()),
+            _ => None,
+        }).unwrap();
+        let result = transform_event(&config, &event);
+        let out: Vec<&String> = result.records.iter().map(|x| &x.record_id).collect();
+        assert_eq!(out, ids.iter().collect::<Vec<_>>(), "PARALLELISM={:?}", parallelism);
+    }
+}
+
+#[test]
+fn transform_event_empty_test() {
+    let event = FirehoseEvent {
+        records: vec![],
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+    let result = transform_event(&Config::default(), &event);
+    assert!(result.records.is_empty());
+    assert_eq!(serde_json::to_value(&result).unwrap(), serde_json::json!({"records": []}));
+}
+
+/// The sequential path, which is all a build without the `parallel` feature
+/// has, must answer exactly as Rayon does.
+#[test]
+#[cfg(feature = "parallel")]
+fn transform_event_sequential_matches_parallel_test() {
+    let lines = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        "garbage",
+        r#"10.0.0.1 - - [14/Dec/2017:22:16:45 +09:00] "GET /health HTTP/1.1" 200 0 "-" "ELB-HealthChecker/2.0""#,
+    ];
+    let event = FirehoseEvent {
+        records: (0..64).map(|i| test_record(&i.to_string(), lines[i % lines.len()])).collect(),
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+    let output = |parallelism: Option<&str>| {
+        let config = Config::from_vars(|key| match key {
+            "PARALLELISM" => parallelism.map(|x| x.to_string()),
+            _ => None,
+        }).unwrap();
+        serde_json::to_string(&transform_event(&config, &event)).unwrap()
+    };
+
+    let sequential = output(Some("1"));
+    assert_eq!(output(None), sequential);
+    assert_eq!(output(Some("2")), sequential);
+}
+
+/// The handler's core on a pool of one thread and of four, as sized by
+/// `RAYON_THREADS`.
+#[test]
+fn transform_event_rayon_threads_test() {
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let event = FirehoseEvent {
+        records: (0..16).map(|i| test_record(&i.to_string(), if i % 4 == 0 { "garbage" } else { line })).collect(),
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+    for &threads in &["1", "4"] {
+        let config = Config::from_vars(|key| if key == "RAYON_THREADS" { Some(threads.to_string()) } else { None });
+        if cfg!(not(feature = "parallel")) && threads != "1" {
+            assert!(config.is_err());
+            continue;
+        }
+        let config = config.unwrap();
+        assert_eq!(config.init_pool().to_string(), threads);
+
+        let result = transform_event(&config, &event);
+        let ids: Vec<&str> = result.records.iter().map(|x| x.record_id.as_str()).collect();
+        assert_eq!(ids, (0..16).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(summarize(&result.records), Summary { ok: 12, failed: 4, dropped: 0 });
+    }
+}
+
+/// Numbers of `OK`, `NG` and `DROPPED` records in one response.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub ok: usize,
+    pub failed: usize,
+    pub dropped: usize,
+}
+
+pub fn summarize(records: &[TransformationRecord]) -> Summary {
+    let count = |result| records.iter().filter(|x| x.result == result).count();
+    Summary { ok: count(OK), failed: count(NG), dropped: count(DROPPED) }
+}
+
+/// Per-invocation result counts, as the single line logged by `my_handler`.
+pub fn invocation_stats(region: &str, summary: &Summary) -> serde_json::Value {
+    serde_json::json!({
+        "ok": summary.ok,
+        "failed": summary.failed,
+        "dropped": summary.dropped,
+        "region": region,
+    })
+}
+
+#[test]
+fn summarize_test() {
+    let record = |result| TransformationRecord {
+        record_id: "1".to_string(),
+        result,
+        data: String::new(),
+        error_detail: None,
+        metadata: None,
+    };
+
+    assert_eq!(summarize(&[]), Summary::default());
+    assert_eq!(summarize(&[record(OK), record(NG), record(OK), record(DROPPED)]), Summary { ok: 2, failed: 1, dropped: 1 });
+    assert_eq!(summarize(&[record(OK), record(OK)]), Summary { ok: 2, failed: 0, dropped: 0 });
+    assert_eq!(summarize(&[record(NG), record(NG), record(NG)]), Summary { ok: 0, failed: 3, dropped: 0 });
+}
+
+#[test]
+fn invocation_stats_test() {
+    let event = FirehoseEvent {
+        records: vec![
+            test_record("1", r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#),
+            test_record("2", "garbage"),
+            test_record("3", r#"10.0.0.1 - - [14/Dec/2017:22:16:45 +09:00] "GET /health HTTP/1.1" 200 0 "-" "ELB-HealthChecker/2.0""#),
+            test_record("4", r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#),
+        ],
+        region: "ap-northeast-1".to_string(),
+        invocation_id: "invocation".to_string(),
+    };
+    let result = transform_event(&Config::default(), &event);
+
+    let summary = summarize(&result.records);
+    assert_eq!(invocation_stats(&event.region, &summary), serde_json::json!({
+        "ok": 2,
+        "failed": 1,
+        "dropped": 1,
+        "region": "ap-northeast-1",
+    }));
+
+    let doc = emf::document("FirehoseTransform", &event.region, summary.ok, summary.failed, summary.dropped, 0);
+    assert_eq!(doc["RecordsOk"], 2);
+    assert_eq!(doc["RecordsFailed"], 1);
+    assert_eq!(doc["RecordsDropped"], 1);
+}
+
+/// Times `transform_event` sequentially and on a two-thread pool, printing
+/// the per-batch cost. Run with
+/// `cargo test --release parallelism_bench_test -- --ignored --nocapture`.
+///
+/// Measured on 1 vCPU there is no crossover: sequential wins at every size,
+/// with the pool's overhead falling from ~60% at 1 record to ~8% at 500
+/// (4.4ms against 4.7ms). Functions at Lambda's smallest sizes should set
+/// `PARALLELISM=1`; rerun this on the target memory size before raising it.
+#[test]
+#[ignore]
+#[cfg(feature = "parallel")]
+fn parallelism_bench_test() {
+    use std::time::Instant;
+
+    let line = BASE64.encode(br#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 "-" "Mozilla/5.0 (Windows NT 10.0; Win64; x64)""#);
+    let configs = [("1", "sequential"), ("2", "pool of 2")];
+    for &size in &[1, 4, 16, 64, 500] {
+        let event = FirehoseEvent {
+            records: (0..size).map(|i| FirehoseRecord {
+                record_id: i.to_string(),
+                data: line.clone(),
+                approximate_arrival_timestamp: 0.0,
+            }).collect(),
+            region: "ap-northeast-1".to_string(),
+            invocation_id: "invocation".to_string(),
+        };
+
+        for &(parallelism, name) in &configs {
+            let config = Config::from_vars(|key| match key {
+                "PARALLELISM" => Some(parallelism.to_string()),
+                _ => None,
+            }).unwrap();
+            let iterations = 20_000 / size;
+            let start = Instant::now();
+            for _ in 0..iterations {
+                transform_event(&config, &event);
+            }
+            println!("{:>4} records, {:<10}: {:?} per event", size, name, start.elapsed() / iterations as u32);
+        }
+    }
+}
+
+/// One parsed access log line. Serializes to the output object, before
+/// `FIELD_MAP` renames.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccessLog<'a> {
+    #[serde(skip)]
+    pub time: DateTime<FixedOffset>,
+    /// As logged, or the masked address when `ANONYMIZE_IP` is set and it
+    /// is one.
+    #[serde(borrow)]
+    pub host: Cow<'a, str>,
+    /// Virtual host that served the request, from `%v`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vhost: Option<&'a str>,
+    /// Server port, from `%p`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Whether `host` is an address rather than a host name.
+    pub host_is_ip: bool,
+    /// `4` or `6` when `host` is an address.
+    pub ip_version: Option<u8>,
+    /// First public address in the `xff` capture, when the format has one.
+    /// Preferred over `host` for the `geo` lookup.
+    pub client_ip: Option<IpAddr>,
+    pub ident: Option<&'a str>,
+    pub authuser: Option<&'a str>,
+    #[serde(rename = "@timestamp")]
+    pub timestamp: Timestamp,
+    #[serde(rename = "@timestamp_utc")]
+    pub timestamp_utc: Timestamp,
+    pub request: &'a str,
+    pub method: &'a str,
+    /// The request's path, percent-decoded up to its query string by
+    /// `decode_path`. `request` keeps the path verbatim.
+    #[serde(borrow)]
+    pub path: Option<Cow<'a, str>>,
+    /// `path` without its query string, percent-decoded, for grouping by
+    /// endpoint.
+    pub url_path: Option<String>,
+    pub protocol: Option<&'a str>,
+    /// `protocol` as `1.0`, `1.1`, `2` or `3`; `None` for any other.
+    pub http_version: Option<&'static str>,
+    pub query: BTreeMap<String, String>,
+    pub response: u16,
+    /// `response` as `2xx`, `4xx` and so on.
+    pub status_class: &'static str,
+    /// From `%b`, or from `%O` in formats without it.
+    pub bytes: Option<u64>,
+    /// Bytes received and sent, headers included, from `%I` and `%O`. `-`
+    /// counts as `0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_received: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_sent: Option<u64>,
+    /// Request duration, normalized to microseconds from `%D` or `%T`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_time_us: Option<u64>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub ua: Option<UserAgent<'a>>,
+    /// Whether woothee takes `user_agent` for a crawler, or it matches one
+    /// of `BOT_UA_PATTERNS`. `false` without a user-agent.
+    pub is_bot: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo: Option<GeoInfo>,
+    /// The line exactly as received, when `RETAIN_RAW` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<&'a str>,
+    /// Other headers the format logs, by name: a string, or an array of
+    /// strings for a header logged more than once.
+    #[serde(flatten)]
+    pub headers: BTreeMap<String, serde_json::Value>,
+    /// Fields that failed to parse and were left out under `LENIENT`.
+    #[serde(rename = "_parse_warnings", skip_serializing_if = "Vec::is_empty", default)]
+    pub parse_warnings: Vec<&'static str>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KinesisEvent {
+    #[serde(rename = "Records")]
+    records: Vec<KinesisRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KinesisRecord {
+    kinesis: KinesisData,
+    #[serde(rename = "eventID")]
+    event_id: String,
+    #[serde(rename = "awsRegion")]
+    aws_region: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KinesisData {
+    #[serde(rename = "partitionKey")]
+    partition_key: String,
+    #[serde(rename = "sequenceNumber")]
+    sequence_number: String,
+    data: String,
+    #[serde(rename = "approximateArrivalTimestamp")]
+    approximate_arrival_timestamp: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KinesisResponse {
+    records: Vec<KinesisResult>,
+}
+
+/// Outcome for one Kinesis record: its NDJSON output unless it failed or was
+/// dropped. With `FANOUT` there is one per output line instead, numbered by
+/// `line`.
+#[derive(Serialize, Debug)]
+struct KinesisResult {
+    #[serde(rename = "sequenceNumber")]
+    sequence_number: String,
+    result: &'static str,
+    data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+}
+
+/// A timestamp rendered according to `TIMESTAMP_FORMAT`: a string for RFC
+/// 3339, a number for the epoch formats.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Text(String),
+    Number(i64),
+}
+
+impl Timestamp {
+    fn new<Tz: TimeZone>(format: TimestampFormat, time: &DateTime<Tz>) -> Timestamp
+        where Tz::Offset: fmt::Display
+    {
+        match format {
+            TimestampFormat::Rfc3339 => Timestamp::Text(time.to_rfc3339()),
+            TimestampFormat::EpochMillis => Timestamp::Number(time.timestamp_millis()),
+            TimestampFormat::EpochSeconds => Timestamp::Number(time.timestamp()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FirehoseEvent {
+    pub records: Vec<FirehoseRecord>,
+    pub region: String,
+    #[serde(rename = "invocationId")]
+    pub invocation_id: String,
+}
+
+/// What the Firehose handler is invoked with: a Firehose event, or the
+/// `{"warmup":true}` a schedule sends to keep the function warm.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum FirehoseInvocation {
+    Event(FirehoseEvent),
+    Warmup { warmup: bool },
+}
+
+impl FirehoseInvocation {
+    /// The event to transform, or `None` for a warmup ping.
+    pub fn event(self) -> Result<Option<FirehoseEvent>, LogError> {
+        match self {
+            FirehoseInvocation::Event(event) => Ok(Some(event)),
+            FirehoseInvocation::Warmup { warmup: true } => Ok(None),
+            FirehoseInvocation::Warmup { warmup: false } => Err(LogError::FormatError("neither a Firehose event nor a warmup".to_string())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FirehoseRecord {
+    #[serde(rename = "recordId")]
+    pub record_id: String,
+    pub data: String,
+    #[serde(rename = "approximateArrivalTimestamp")]
+    pub approximate_arrival_timestamp: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TransformationEvent {
+    pub records: Vec<TransformationRecord>,
+}
+
+#[cfg(test)]
+static FIREHOSE_EVENT: &str = r#"{
+  "invocationId": "8e5e1f7e-4c5b-4a6b-9b8a-0e0d6f1c2a3b",
+  "deliveryStreamArn": "arn:aws:firehose:ap-northeast-1:123456789012:deliverystream/access-logs",
+  "region": "ap-northeast-1",
+  "records": [
+    {
+      "recordId": "49546986683135544286507457936321625675700192471156785154",
+      "approximateArrivalTimestamp": 1513257405453,
+      "data": "Ny4yNDguNy4xMTkgLSAtIFsxNC9EZWMvMjAxNzoyMjoxNjo0NSArMDk6MDBdICJHRVQgL2V4cGxvcmUiIDIwMCA5OTQ3"
+    },
+    {
+      "recordId": "49546986683135544286507457936321625675700192471156785155",
+      "approximateArrivalTimestamp": 1513257405454,
+      "data": "Z2FyYmFnZQ=="
+    }
+  ]
+}"#;
+
+#[test]
+fn firehose_event_deserialize_test() {
+    let event: FirehoseEvent = serde_json::from_str(FIREHOSE_EVENT).unwrap();
+
+    assert_eq!(event.invocation_id, "8e5e1f7e-4c5b-4a6b-9b8a-0e0d6f1c2a3b");
+    assert_eq!(event.region, "ap-northeast-1");
+    assert_eq!(event.records.len(), 2);
+    assert_eq!(event.records[0].record_id, "49546986683135544286507457936321625675700192471156785154");
+    assert_eq!(event.records[0].approximate_arrival_timestamp, 1513257405453.0);
+
+    let value = serde_json::to_value(&event.records[0]).unwrap();
+    let keys: Vec<&String> = value.as_object().unwrap().keys().collect();
+    assert_eq!(keys, ["approximateArrivalTimestamp", "data", "recordId"]);
+}
+
+#[test]
+fn firehose_invocation_test() {
+    let invocation: FirehoseInvocation = serde_json::from_str(FIREHOSE_EVENT).unwrap();
+    assert_eq!(invocation.event().unwrap().unwrap().records.len(), 2);
+
+    let invocation: FirehoseInvocation = serde_json::from_str(r#"{"warmup":true}"#).unwrap();
+    assert!(invocation.event().unwrap().is_none());
+
+    let invocation: FirehoseInvocation = serde_json::from_str(r#"{"warmup":false}"#).unwrap();
+    assert!(invocation.event().is_err());
+    assert!(serde_json::from_str::<FirehoseInvocation>(r#"{"records":[]}"#).is_err());
+}
+
+/// Firehose drops responses whose keys don't match exactly, so a typo in a
+/// rename would only show up as failed deliveries.
+#[test]
+fn transformation_event_serialize_test() {
+    let event: FirehoseEvent = serde_json::from_str(FIREHOSE_EVENT).unwrap();
+    let value = serde_json::to_value(transform_event(&Config::default(), &event)).unwrap();
+
+    let keys: Vec<&String> = value.as_object().unwrap().keys().collect();
+    assert_eq!(keys, ["records"]);
+
+    let records = value["records"].as_array().unwrap();
+    let keys: Vec<&String> = records[0].as_object().unwrap().keys().collect();
+    assert_eq!(keys, ["data", "recordId", "result"]);
+    assert_eq!(records[0]["recordId"], "49546986683135544286507457936321625675700192471156785154");
+    assert_eq!(records[0]["result"], "Ok");
+
+    let keys: Vec<&String> = records[1].as_object().unwrap().keys().collect();
+    assert_eq!(keys, ["data", "error_detail", "recordId", "result"]);
+    assert_eq!(records[1]["result"], "ProcessingFailed");
+}
+
+static OK: &str = "Ok";
+static NG: &str = "ProcessingFailed";
+static DROPPED: &str = "Dropped";
+
+#[derive(Serialize, Debug)]
+pub struct TransformationRecord {
+    #[serde(rename = "recordId")]
+    record_id: String,
+    result: &'static str,
+    data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_detail: Option<ErrorDetail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+}
+
+/// Record metadata read by Firehose dynamic partitioning.
+#[derive(Serialize, Debug, PartialEq)]
+struct Metadata {
+    #[serde(rename = "partitionKeys")]
+    partition_keys: BTreeMap<String, String>,
+}
+
+/// Why a record was marked `ProcessingFailed`.
+#[derive(Serialize, Debug, PartialEq)]
+struct ErrorDetail {
+    /// `LogError` variant name.
+    error_type: &'static str,
+    /// First line that failed to parse, truncated to 1KB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<String>,
+}
\ No newline at end of file
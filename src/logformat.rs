@@ -0,0 +1,267 @@
+use regex::{self, Regex};
+
+use LogError;
+use config::FieldSeparator;
+
+/// The formats tab-separated lines are read with when there is no
+/// `LOG_FORMAT`: Combined, then Common, without their quotes.
+pub static TSV_FORMATS: &[&str] = &[
+    "%h %l %u %t %r %>s %b %{Referer}i %{User-Agent}i",
+    "%h %l %u %t %r %>s %b",
+];
+
+/// Translates an Apache `LogFormat` string (e.g. `%h %l %u %t "%r" %>s %b`)
+/// into a regex with one named capture group per directive.
+///
+/// Group names match the ones `parse_access_log` looks up: `host`, `ident`,
+/// `authuser`, `time`, `request`, `status`, `bytes`, and a lowercased,
+/// underscored header name for `%{Header}i` (`referer`, `user_agent`, ...),
+/// except `X-Forwarded-For`, which is captured as `xff`. Request durations
+/// are captured by unit: `response_time_us` for `%D` and `%{us}T`,
+/// `response_time_ms` for `%{ms}T`, and `response_time_s` for `%T` and
+/// `%{s}T`. `%v` and `%p` are captured as `vhost` and `port`, and `%I` and
+/// `%O` as `bytes_received` and `bytes_sent`.
+/// A directive that repeats a name already captured is matched but not
+/// captured again.
+///
+/// Any other request header, and any `%{Header}o` response header, is
+/// captured as `header_<n>_<name>`, numbered so that a header logged more
+/// than once keeps every value.
+///
+/// `%{format}t` is captured as `time` too, without the brackets of `%t`,
+/// and its strftime layout is returned for parsing it.
+pub fn compile_format(fmt: &str) -> Result<CompiledFormat, LogError> {
+    compile_separated_format(fmt, FieldSeparator::Space)
+}
+
+/// `compile_format` for lines whose fields are split by `separator`. With
+/// `Tab`, the spaces of `fmt` stand for tabs, text fields may hold spaces,
+/// and the brackets of `%t` are optional.
+pub fn compile_separated_format(fmt: &str, separator: FieldSeparator) -> Result<CompiledFormat, LogError> {
+    let tab = separator == FieldSeparator::Tab;
+    let mut pattern = String::from("^");
+    let mut names: Vec<String> = Vec::new();
+    let mut time_format = None;
+    let mut headers = 0;
+    let mut chars = fmt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => pattern.push_str(r"\t"),
+                Some(escaped) => pattern.push_str(&regex::escape(&escaped.to_string())),
+                None => {}
+            },
+            ' ' if tab => pattern.push_str(r"\t"),
+            '%' => {
+                if chars.peek() == Some(&'%') {
+                    chars.next();
+                    pattern.push('%');
+                    continue;
+                }
+
+                let mut arg = None;
+                if chars.peek() == Some(&'{') {
+                    chars.next();
+                    arg = Some(chars.by_ref().take_while(|&x| x != '}').collect::<String>());
+                }
+                while chars.peek() == Some(&'>') || chars.peek() == Some(&'<') {
+                    chars.next();
+                }
+
+                let directive = chars.next()
+                    .ok_or_else(|| LogError::FormatError(fmt.to_string()))?;
+                let custom_time = directive == 't' && arg.is_some();
+                let (name, re) = match (directive, arg) {
+                    ('h', None) => ("host".to_string(), r"\S+"),
+                    ('l', None) => ("ident".to_string(), r"\S+"),
+                    ('u', None) => ("authuser".to_string(), r"\S+"),
+                    ('t', None) => ("time".to_string(), r"[^\]]+"),
+                    ('t', Some(layout)) => {
+                        let layout = chrono_layout(&layout)?;
+                        time_format.get_or_insert(layout);
+                        ("time".to_string(), r".+?")
+                    }
+                    ('r', None) => ("request".to_string(), r"(?:\\.|[^\\\n])+?"),
+                    ('s', None) => ("status".to_string(), r"\d{3}"),
+                    ('b', None) => ("bytes".to_string(), r"\d+|-"),
+                    ('I', None) => ("bytes_received".to_string(), r"\d+|-"),
+                    ('O', None) => ("bytes_sent".to_string(), r"\d+|-"),
+                    ('i', Some(header)) | ('o', Some(header)) => match header_group_name(&header) {
+                        ref x if directive == 'i' && KNOWN_HEADERS.contains(&x.as_str()) => (x.to_string(), r#"[^"]*"#),
+                        x => {
+                            headers += 1;
+                            (format!("{}{}_{}", HEADER_PREFIX, headers, x), r#"[^"]*"#)
+                        }
+                    },
+                    ('v', None) => ("vhost".to_string(), r"[^\s:]+"),
+                    ('p', None) => ("port".to_string(), r"\d+"),
+                    ('D', None) => ("response_time_us".to_string(), r"\d+|-"),
+                    ('T', None) => ("response_time_s".to_string(), r"\d+|-"),
+                    ('T', Some(unit)) => match unit.as_str() {
+                        "us" => ("response_time_us".to_string(), r"\d+|-"),
+                        "ms" => ("response_time_ms".to_string(), r"\d+|-"),
+                        "s" => ("response_time_s".to_string(), r"\d+|-"),
+                        _ => return Err(LogError::FormatError(format!("unsupported unit %{{{}}}T", unit))),
+                    },
+                    (d, _) => return Err(LogError::FormatError(format!("unsupported directive %{}", d))),
+                };
+
+                let re = match directive {
+                    'h' | 'l' | 'u' | 'r' if tab => r"[^\t]+",
+                    't' if tab && custom_time => r"[^\t]+",
+                    't' if tab => r"[^\t\]]+",
+                    'i' | 'o' if tab => r"[^\t]*",
+                    _ => re,
+                };
+
+                let bracketed = directive == 't' && !custom_time;
+                if bracketed {
+                    pattern.push_str(if tab { r"\[?" } else { r"\[" });
+                }
+                if names.contains(&name) {
+                    pattern.push_str(&format!("(?:{})", re));
+                } else {
+                    pattern.push_str(&format!("(?P<{}>{})", name, re));
+                    names.push(name);
+                }
+                if bracketed {
+                    pattern.push_str(if tab { r"\]?" } else { r"\]" });
+                }
+            }
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    Ok(CompiledFormat { pattern: Regex::new(&pattern)?, time_format })
+}
+
+/// A `LogFormat` as a regex, with the layout of its `%{format}t`, if any.
+pub struct CompiledFormat {
+    pub pattern: Regex,
+    pub time_format: Option<String>,
+}
+
+/// The `chrono` format for the layout of a `%{format}t`, which is strftime
+/// apart from the `begin:` and `end:` prefixes and `sec`, for epoch
+/// seconds. The other epoch forms aren't supported.
+fn chrono_layout(layout: &str) -> Result<String, LogError> {
+    let layout = layout.strip_prefix("begin:").or_else(|| layout.strip_prefix("end:")).unwrap_or(layout);
+    match layout {
+        "sec" => Ok("%s".to_string()),
+        "msec" | "usec" | "msec_frac" | "usec_frac" => Err(LogError::FormatError(format!("unsupported time %{{{}}}t", layout))),
+        x => Ok(x.to_string()),
+    }
+}
+
+/// Request headers `parse_access_log` reads into fields of their own.
+static KNOWN_HEADERS: &[&str] = &["referer", "user_agent", "xff"];
+
+/// Prefix of the groups holding any other header.
+pub static HEADER_PREFIX: &str = "header_";
+
+/// The output name of a `header_<n>_<name>` group.
+pub fn header_field(group: &str) -> Option<&str> {
+    let rest = group.strip_prefix(HEADER_PREFIX)?;
+    let i = rest.find('_')?;
+    if i == 0 || !rest[..i].bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    Some(&rest[i + 1..])
+}
+
+fn header_group_name(header: &str) -> String {
+    let name: String = header.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    if name == "x_forwarded_for" { "xff".to_string() } else { name }
+}
+
+#[test]
+fn compile_format_combined_test() {
+    let re = compile_format(r#"%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\""#).unwrap().pattern;
+    let line = r#"7.248.7.119 - frank [14/Dec/2017:22:16:45 +09:00] "GET /explore HTTP/1.1" 200 9947 "-" "Mozilla/5.0""#;
+    let xs = re.captures(line).unwrap();
+
+    assert_eq!(&xs["host"], "7.248.7.119");
+    assert_eq!(&xs["authuser"], "frank");
+    assert_eq!(&xs["time"], "14/Dec/2017:22:16:45 +09:00");
+    assert_eq!(&xs["request"], "GET /explore HTTP/1.1");
+    assert_eq!(&xs["status"], "200");
+    assert_eq!(&xs["bytes"], "9947");
+    assert_eq!(&xs["referer"], "-");
+    assert_eq!(&xs["user_agent"], "Mozilla/5.0");
+}
+
+#[test]
+fn compile_format_escaped_request_test() {
+    let re = compile_format(r#"%h %l %u %t \"%r\" %>s %b"#).unwrap().pattern;
+    let line = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /a\" 200 1 HTTP/1.1" 200 9947"#;
+    assert_eq!(&re.captures(line).unwrap()["request"], r#"GET /a\" 200 1 HTTP/1.1"#);
+}
+
+#[test]
+fn compile_separated_format_test() {
+    let re = compile_separated_format(TSV_FORMATS[0], FieldSeparator::Tab).unwrap().pattern;
+    let xs = re.captures("7.248.7.119\t-\tfrank smith\t14/Dec/2017:22:16:45 +09:00\tGET /explore HTTP/1.1\t200\t9947\t-\tcurl/7.46.0 (x86_64)").unwrap();
+    assert_eq!(&xs["authuser"], "frank smith");
+    assert_eq!(&xs["time"], "14/Dec/2017:22:16:45 +09:00");
+    assert_eq!(&xs["request"], "GET /explore HTTP/1.1");
+    assert_eq!(&xs["user_agent"], "curl/7.46.0 (x86_64)");
+
+    let xs = re.captures("7.248.7.119\t-\t-\t[14/Dec/2017:22:16:45 +09:00]\tGET /\t200\t1\t\t").unwrap();
+    assert_eq!(&xs["time"], "14/Dec/2017:22:16:45 +09:00");
+    assert_eq!(&xs["referer"], "");
+    assert!(re.captures("7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] \"GET /\" 200 1").is_none());
+
+    let re = compile_format(r"%h\t%>s").unwrap().pattern;
+    assert_eq!(&re.captures("7.248.7.119\t200").unwrap()["status"], "200");
+}
+
+#[test]
+fn compile_format_unsupported_directive_test() {
+    assert!(compile_format("%h %Z").is_err());
+}
+
+#[test]
+fn compile_format_xff_test() {
+    let re = compile_format(r#"%h %t \"%r\" %>s %b \"%{X-Forwarded-For}i\""#).unwrap().pattern;
+    let line = r#"10.0.0.1 [14/Dec/2017:22:16:45 +09:00] "GET / HTTP/1.1" 200 10 "7.248.7.119, 10.0.0.2""#;
+    assert_eq!(&re.captures(line).unwrap()["xff"], "7.248.7.119, 10.0.0.2");
+}
+
+#[test]
+fn compile_format_headers_test() {
+    let re = compile_format(r#"%h \"%{X-Custom}i\" \"%{X-Custom}i\" \"%{Set-Cookie}o\""#).unwrap().pattern;
+    let xs = re.captures(r#"7.248.7.119 "a" "b" "id=1""#).unwrap();
+    assert_eq!(&xs["header_1_x_custom"], "a");
+    assert_eq!(&xs["header_2_x_custom"], "b");
+    assert_eq!(&xs["header_3_set_cookie"], "id=1");
+
+    assert_eq!(header_field("header_12_x_custom"), Some("x_custom"));
+    assert_eq!(header_field("header_x_custom"), None);
+    assert_eq!(header_field("host"), None);
+}
+
+#[test]
+fn compile_format_duration_test() {
+    let re = compile_format("%h %>s %D %{ms}T %T").unwrap().pattern;
+    let xs = re.captures("7.248.7.119 200 1500 1 0").unwrap();
+    assert_eq!(&xs["response_time_us"], "1500");
+    assert_eq!(&xs["response_time_ms"], "1");
+    assert_eq!(&xs["response_time_s"], "0");
+
+    assert!(compile_format("%h %{ns}T").is_err());
+}
+
+#[test]
+fn compile_format_custom_time_test() {
+    let format = compile_format(r#"%h %{%Y-%m-%dT%H:%M:%S%z}t \"%r\" %>s %b"#).unwrap();
+    let xs = format.pattern.captures(r#"7.248.7.119 2017-12-14T22:16:45+0900 "GET / HTTP/1.1" 200 10"#).unwrap();
+    assert_eq!(&xs["time"], "2017-12-14T22:16:45+0900");
+    assert_eq!(format.time_format.as_deref(), Some("%Y-%m-%dT%H:%M:%S%z"));
+
+    assert_eq!(compile_format("%h %{end:%d/%b/%Y}t").unwrap().time_format.as_deref(), Some("%d/%b/%Y"));
+    assert_eq!(compile_format("%h %t").unwrap().time_format, None);
+    assert!(compile_format("%h %{msec}t").is_err());
+}
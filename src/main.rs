@@ -1,214 +1,224 @@
 extern crate lambda_runtime as lambda;
 
-extern crate log;
-extern crate simple_logger;
+extern crate tracing;
+extern crate tracing_subscriber;
 
 extern crate chrono;
-extern crate data_encoding;
-
-extern crate serde;
 extern crate serde_json;
-extern crate serde_derive;
 
 #[macro_use]
 extern crate lazy_static;
-extern crate regex;
-extern crate rayon;
 
-use serde_derive::{Serialize, Deserialize};
+extern crate custom_lambda_rs;
+
 use lambda::{lambda, Context, error::HandlerError};
 use std::error::Error;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process;
 
-use std::fmt;
 use chrono::prelude::*;
-use data_encoding::BASE64;
-use regex::Regex;
-use rayon::prelude::*;
+use custom_lambda_rs::config::Config;
+use custom_lambda_rs::{alert, emf, metrics, s3, LogError};
+use custom_lambda_rs::{FirehoseInvocation, KinesisEvent, KinesisResponse, LineParser, TransformationEvent};
 
 lazy_static! {
-    static ref RE: Regex = Regex::new(r#"^([\d.]+) (\S+) (\S+) \[([\w:/]+\s[\+\-]\d{2}:?\d{2}){0,1}\] "(.+?)" (\d{3}) (\d+)"#).unwrap();
+    static ref CONFIG: Result<Config, LogError> = Config::from_env();
+    static ref S3_STORE: Result<s3::S3Store, LogError> = s3::S3Store::new();
+    /// Only connected when there is a topic to publish to.
+    static ref SNS: Option<Result<alert::SnsNotifier, LogError>> = match *CONFIG {
+        Ok(ref config) if config.sns_topic_arn.is_some() => Some(alert::SnsNotifier::new()),
+        _ => None,
+    };
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    simple_logger::init_with_level(log::Level::Error).unwrap();
-    lambda!(my_handler);
-
-    Ok(())
-}
-
-#[derive(Debug)]
-enum LogError {
-    RegexParseError,
-    UTF8Error(std::string::FromUtf8Error),
-    EncodingError(data_encoding::DecodeError),
-    DateTimeParseError(chrono::ParseError),
-    IntError(std::num::ParseIntError),
-    JsonError(serde_json::Error)
-}
-
-impl From<std::string::FromUtf8Error> for LogError {
-    fn from(err: std::string::FromUtf8Error) -> LogError {
-        LogError::UTF8Error(err)
+    let level = env::var("LOG_LEVEL").ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(tracing::Level::ERROR);
+    tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_max_level(level)
+        .with_writer(io::stderr)
+        .init();
+    let config = match *CONFIG {
+        Ok(ref config) => config,
+        Err(ref e) => {
+            tracing::error!("invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(path) = arg_value("--validate") {
+        let max_failure_ratio = match arg_value("--max-failure-ratio").map(|x| x.parse()) {
+            Some(Ok(x)) => x,
+            Some(Err(e)) => {
+                eprintln!("invalid --max-failure-ratio: {}", e);
+                process::exit(2);
+            }
+            None => 0.0,
+        };
+        if !validate(config, &path, max_failure_ratio)? {
+            process::exit(1);
+        }
+        return Ok(());
     }
-}
-
-impl From<data_encoding::DecodeError> for LogError {
-    fn from(err: data_encoding::DecodeError) -> LogError {
-        LogError::EncodingError(err)
+    if env::args().any(|x| x == "--firehose") {
+        return firehose_filter();
     }
-}
-
-impl From<chrono::ParseError> for LogError {
-    fn from(err: chrono::ParseError) -> LogError {
-        LogError::DateTimeParseError(err)
+    if env::args().any(|x| x == "--stdin") || env::var("AWS_LAMBDA_RUNTIME_API").is_err() {
+        return stdin_filter(config);
     }
-}
-
-impl From<std::num::ParseIntError> for LogError {
-    fn from(err: std::num::ParseIntError) -> LogError {
-        LogError::IntError(err)
+    tracing::info!(threads = config.init_pool(), "record pool ready");
+    match env::var("HANDLER_MODE").as_ref().map(|x| x.as_str()) {
+        Ok("kinesis") => lambda!(kinesis_handler),
+        Ok("s3") => lambda!(s3_handler),
+        _ => lambda!(my_handler),
     }
-}
 
-impl From<serde_json::Error> for LogError {
-    fn from(err: serde_json::Error) -> LogError {
-        LogError::JsonError(err)
-    }
+    Ok(())
 }
 
-impl fmt::Display for LogError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            LogError::RegexParseError => fmt::Display::fmt(self, f),
-            LogError::UTF8Error(ref err) => fmt::Display::fmt(err, f),
-            LogError::EncodingError(ref err) => fmt::Display::fmt(err, f),
-            LogError::DateTimeParseError(ref err) => fmt::Display::fmt(err, f),
-            LogError::IntError(ref err) => fmt::Display::fmt(err, f),
-            LogError::JsonError(ref err) => fmt::Display::fmt(err, f),
+/// Local mode: parses each line of stdin as `LineParser` does and prints
+/// its JSON to stdout. Lines that fail are reported on stderr with their
+/// line number. With `--metrics`, the parse counters follow them on stderr
+/// at the end.
+fn stdin_filter(config: &Config) -> Result<(), Box<dyn Error>> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut parser = LineParser::new(config);
+    for (i, line) in stdin.lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
         }
-    }
-}
-
-impl std::error::Error for LogError {
-    fn description(&self) -> &str {
-        match *self {
-            LogError::RegexParseError => "FAIL. unmatched pattern.",
-            LogError::UTF8Error(ref err) => err.description(),
-            LogError::EncodingError(ref err) => err.description(),
-            LogError::DateTimeParseError(ref err) => err.description(),
-            LogError::IntError(ref err) => err.description(),
-            LogError::JsonError(ref err) => err.description(),
+        match metrics::METRICS.observe(|| parser.parse(&line)) {
+            Ok(Some(x)) => writeln!(out, "{}", x)?,
+            Ok(None) => {}
+            Err(e) => eprintln!("line {}: {}", i + 1, e),
         }
     }
+    out.flush()?;
+    if env::args().any(|x| x == "--metrics") {
+        metrics::dump_metrics();
+    }
+    Ok(())
 }
 
-fn apache_log2json(s: &str) -> Result<serde_json::Value, LogError> {
-    let xs = RE.captures(s).ok_or(LogError::RegexParseError)?;
-
-    let time =
-        DateTime::parse_from_str(&xs[4], "%d/%b/%Y:%H:%M:%S %:z")
-            .or_else(|_| DateTime::parse_from_str(&xs[4], "%d/%b/%Y:%H:%M:%S %z"))?;
-
-    let log =  AccessLog {
-        host: &xs[1],
-        ident: &xs[2],
-        authuser: &xs[3],
-        timestamp: time.to_rfc3339(),
-        timestamp_utc: time.with_timezone(&Utc).to_rfc3339(),
-        request: &xs[5],
-        response: xs[6].parse::<u32>()?,
-        bytes: xs[7].parse::<u32>()?,
-    };
-    serde_json::to_value(log).map_err(|e| LogError::JsonError(e))
+/// `--firehose`: runs the Firehose handler on a whole event read from
+/// stdin, as Lambda would, and prints its response to stdout. The EMF
+/// document goes to stderr, so that stdout is the response alone.
+fn firehose_filter() -> Result<(), Box<dyn Error>> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let invocation: FirehoseInvocation = serde_json::from_str(&input)?;
+    let response = handle_firehose(invocation, Context::default(), |doc| eprintln!("{}", doc))?;
+    println!("{}", serde_json::to_string(&response)?);
+    Ok(())
 }
 
-fn transform_data(data: Vec<u8>) -> std::result::Result<Vec<u8>, LogError> {
-    let s = String::from_utf8(data)?;
-    let r = apache_log2json(&s)?;
-    serde_json::to_vec(&r).map_err(|e| LogError::JsonError(e))
+/// The argument following `name`, e.g. the file of `--validate <file>`.
+fn arg_value(name: &str) -> Option<String> {
+    env::args().skip_while(|x| x != name).nth(1)
 }
 
-#[test]
-fn transform_data_test() {
-    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947 "-" "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1" "#;
-    let a = apache_log2json(data).unwrap();
-
-    println!("{}", a);
-}
+/// Failing lines `validate` prints before only counting the rest.
+static VALIDATE_SHOWN_FAILURES: usize = 5;
 
-fn transform_record(record: &FirehoseRecord) -> TransformationRecord {
-    BASE64.decode(record.data.as_bytes()).map_err(|e| LogError::EncodingError(e))
-        .and_then(|x|
-            transform_data(x)
-                .map(|x|
-                    TransformationRecord {
-                        record_id: record.record_id.to_string(),
-                        data: BASE64.encode(&x),
-                        result: OK,
-                    }
-                )
-        )
-        .unwrap_or_else(|_|
-            TransformationRecord {
-                record_id: record.record_id.to_string(),
-                data: record.data.to_string(),
-                result: NG,
+/// `--validate <file>`: parses every line of `path` with the configured
+/// flavor and formats and prints how many parsed, with the first failures.
+/// Returns whether the share of failed lines is within `max_failure_ratio`,
+/// given by `--max-failure-ratio` and 0 by default.
+fn validate(config: &Config, path: &str, max_failure_ratio: f64) -> Result<bool, Box<dyn Error>> {
+    let mut parser = LineParser::new(config);
+    let (mut total, mut failed) = (0, 0);
+    let mut failures = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parser.parse(&line) {
+            Ok(None) => continue,
+            Ok(Some(_)) => total += 1,
+            Err(e) => {
+                total += 1;
+                failed += 1;
+                if failures.len() < VALIDATE_SHOWN_FAILURES {
+                    failures.push(format!("line {}: {}", i + 1, e));
+                }
             }
-        )
-}
+        }
+    }
 
-fn my_handler<'a>(event: FirehoseEvent, _: Context) -> Result<TransformationEvent, HandlerError> {
-    let records = event.records
-        .par_iter()
-        .map(|x| transform_record(x))
-        .collect();
+    let ratio = if total == 0 { 0.0 } else { failed as f64 / total as f64 };
+    println!("total: {}", total);
+    println!("parsed: {}", total - failed);
+    println!("failed: {}", failed);
+    for x in &failures {
+        println!("{}", x);
+    }
+    let ok = ratio <= max_failure_ratio;
+    println!("{} (failure ratio {:.4}, max {})", if ok { "PASS" } else { "FAIL" }, ratio, max_failure_ratio);
+    Ok(ok)
+}
+
+/// Firehose transformation entry point. The response keeps the records in
+/// the order they came in, which Firehose relies on; `transform_event`
+/// guarantees it. Warmup pings get an empty response, and leave the metrics
+/// alone. Under `DRY_RUN` the metrics and alerts still come from the
+/// transform, but the records go back untouched.
+fn my_handler(invocation: FirehoseInvocation, ctx: Context) -> Result<TransformationEvent, HandlerError> {
+    handle_firehose(invocation, ctx, emf::emit)
+}
+
+/// `my_handler`, writing its EMF document with `emit`.
+fn handle_firehose(invocation: FirehoseInvocation, ctx: Context, emit: fn(&serde_json::Value)) -> Result<TransformationEvent, HandlerError> {
+    let config = CONFIG.as_ref().map_err(|e| ctx.new_error(&e.to_string()))?;
+    let event = match invocation.event().map_err(|e| ctx.new_error(&e.to_string()))? {
+        Some(event) => event,
+        None => {
+            tracing::info!("warmup");
+            return Ok(TransformationEvent { records: Vec::new() });
+        }
+    };
+    let result = custom_lambda_rs::transform_event(config, &event);
+    let summary = custom_lambda_rs::summarize(&result.records);
+    tracing::info!("{}", custom_lambda_rs::invocation_stats(&event.region, &summary));
 
-    Ok(TransformationEvent { records })
-}
+    emit(&emf::document(&config.metric_namespace, &event.region, summary.ok, summary.failed, summary.dropped, Utc::now().timestamp_millis()));
 
-#[derive(Serialize, Deserialize, Debug)]
-struct AccessLog<'a> {
-    host: &'a str,
-    ident: &'a str,
-    authuser: &'a str,
-    #[serde(rename = "@timestamp")]
-    timestamp: String,
-    #[serde(rename = "@timestamp_utc")]
-    timestamp_utc: String,
-    request: &'a str,
-    response: u32,
-    bytes: u32,
-}
+    let notifier = match *SNS {
+        Some(Ok(ref x)) => Some(x as &dyn alert::Notifier),
+        Some(Err(ref e)) => {
+            tracing::error!("SNS unavailable: {}", e);
+            None
+        }
+        None => None,
+    };
+    if let Err(e) = alert::check(config, notifier, &event.region, &summary) {
+        tracing::error!("alert failed: {}", e);
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct FirehoseEvent {
-    records: Vec<FirehoseRecord>,
-    region: String,
-    #[serde(rename = "invocationId")]
-    invocation_id: String,
+    if config.dry_run {
+        return Ok(custom_lambda_rs::dry_run_response(&event));
+    }
+    Ok(result)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct FirehoseRecord {
-    #[serde(rename = "recordId")]
-    record_id: String,
-    data: String,
-    #[serde(rename = "approximateArrivalTimestamp")]
-    approximate_arrival_timestamp: f64,
+fn kinesis_handler(event: KinesisEvent, ctx: Context) -> Result<KinesisResponse, HandlerError> {
+    let config = CONFIG.as_ref().map_err(|e| ctx.new_error(&e.to_string()))?;
+    Ok(custom_lambda_rs::transform_kinesis_event(config, &event))
 }
 
-#[derive(Serialize, Debug)]
-struct TransformationEvent {
-    records: Vec<TransformationRecord>,
+fn s3_handler(event: s3::S3Event, ctx: Context) -> Result<Vec<s3::ObjectResult>, HandlerError> {
+    let config = CONFIG.as_ref().map_err(|e| ctx.new_error(&e.to_string()))?;
+    let store = S3_STORE.as_ref().map_err(|e| ctx.new_error(&e.to_string()))?;
+    let results = s3::transform_event(config, store, &event).map_err(|e| ctx.new_error(&e.to_string()))?;
+    for x in &results {
+        tracing::info!("{}", serde_json::json!(x));
+    }
+    Ok(results)
 }
-
-static OK: &'static str = "Ok";
-static NG: &'static str = "ProcessingFailed";
-
-#[derive(Serialize, Debug)]
-struct TransformationRecord {
-    #[serde(rename = "recordId")]
-    record_id: String,
-    result: &'static str,
-    data: String,
-}
\ No newline at end of file
@@ -0,0 +1,97 @@
+//! Parse counters in the Prometheus text exposition format, for load testing
+//! the parser locally through the stdin mode. The Lambda handlers report
+//! through `emf` instead and don't touch these.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Counters shared by every thread parsing lines.
+pub struct Metrics {
+    lines_parsed: AtomicU64,
+    lines_failed: AtomicU64,
+    parse_nanos: AtomicU64,
+}
+
+/// The counters of the stdin mode.
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            lines_parsed: AtomicU64::new(0),
+            lines_failed: AtomicU64::new(0),
+            parse_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `parse` on one line, counting it as parsed or failed and adding
+    /// the time it took.
+    pub fn observe<T, E, F: FnOnce() -> Result<T, E>>(&self, parse: F) -> Result<T, E> {
+        let start = Instant::now();
+        let result = parse();
+        self.parse_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        let counter = if result.is_ok() { &self.lines_parsed } else { &self.lines_failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    /// The counters in the text exposition format, with the parse time as
+    /// a summary of every line.
+    pub fn exposition(&self) -> String {
+        let parsed = self.lines_parsed.load(Ordering::Relaxed);
+        let failed = self.lines_failed.load(Ordering::Relaxed);
+        let seconds = self.parse_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP lines_parsed_total Lines parsed into JSON.");
+        let _ = writeln!(out, "# TYPE lines_parsed_total counter");
+        let _ = writeln!(out, "lines_parsed_total {}", parsed);
+        let _ = writeln!(out, "# HELP lines_failed_total Lines that failed to parse.");
+        let _ = writeln!(out, "# TYPE lines_failed_total counter");
+        let _ = writeln!(out, "lines_failed_total {}", failed);
+        let _ = writeln!(out, "# HELP parse_duration_seconds Time spent parsing lines.");
+        let _ = writeln!(out, "# TYPE parse_duration_seconds summary");
+        let _ = writeln!(out, "parse_duration_seconds_sum {}", seconds);
+        let _ = writeln!(out, "parse_duration_seconds_count {}", parsed + failed);
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+/// Prints `METRICS` to stderr, leaving stdout to the parsed lines.
+pub fn dump_metrics() {
+    eprint!("{}", METRICS.exposition());
+}
+
+#[test]
+fn exposition_test() {
+    use config::Config;
+    use log2json;
+
+    let config = Config::default();
+    let metrics = Metrics::new();
+    let lines = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        "garbage",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#,
+        r#"7.248.7.121 - - [14/Dec/2017:22:16:47 +09:00] "GET /" 200 1"#,
+    ];
+    for line in &lines {
+        let _ = metrics.observe(|| log2json(&config, line));
+    }
+
+    let text = metrics.exposition();
+    let samples: Vec<&str> = text.lines().filter(|x| !x.starts_with('#')).collect();
+    assert_eq!(samples[0], "lines_parsed_total 3");
+    assert_eq!(samples[1], "lines_failed_total 1");
+    assert!(samples[2].starts_with("parse_duration_seconds_sum "));
+    assert!(samples[2]["parse_duration_seconds_sum ".len()..].parse::<f64>().unwrap() > 0.0);
+    assert_eq!(samples[3], "parse_duration_seconds_count 4");
+    assert!(text.contains("# TYPE lines_parsed_total counter\n"));
+}
@@ -0,0 +1,142 @@
+//! `TRANSFORM_RULES`: small edits to each output object, written as calls
+//! separated by `;`, e.g. `lower(method); strip_port(host)`.
+//!
+//! Rules name fields by their default names and run in order, before
+//! `EXCLUDE_FIELDS` and `FIELD_MAP`. A rule whose field is missing, or not
+//! a string where one is needed, leaves the object alone.
+
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use LogError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    /// `lower(field)`
+    Lower(String),
+    /// `upper(field)`
+    Upper(String),
+    /// `strip_port(field)`: `host:port` or `[v6]:port` to the host alone.
+    StripPort(String),
+    /// `rename(from, to)`
+    Rename(String, String),
+    /// `remove(field)`
+    Remove(String),
+}
+
+impl FromStr for Rule {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<Rule, LogError> {
+        let invalid = || LogError::FormatError(format!("invalid transform rule {}", s));
+        let open = s.find('(').ok_or_else(invalid)?;
+        let args = s[open + 1..].strip_suffix(')').ok_or_else(invalid)?;
+        let args: Vec<String> = args.split(',').map(|x| x.trim().to_string()).collect();
+        if args.iter().any(|x| x.is_empty()) {
+            return Err(invalid());
+        }
+
+        let name = s[..open].trim();
+        match (name, args.len()) {
+            ("lower", 1) => Ok(Rule::Lower(args[0].to_string())),
+            ("upper", 1) => Ok(Rule::Upper(args[0].to_string())),
+            ("strip_port", 1) => Ok(Rule::StripPort(args[0].to_string())),
+            ("rename", 2) => Ok(Rule::Rename(args[0].to_string(), args[1].to_string())),
+            ("remove", 1) => Ok(Rule::Remove(args[0].to_string())),
+            ("lower", _) | ("upper", _) | ("strip_port", _) | ("rename", _) | ("remove", _) => {
+                Err(LogError::FormatError(format!("wrong number of arguments to {}", name)))
+            }
+            _ => Err(LogError::FormatError(format!("unknown transform function {}", name))),
+        }
+    }
+}
+
+/// Parses a `TRANSFORM_RULES` value; empty rules between `;`s are skipped.
+pub fn parse(s: &str) -> Result<Vec<Rule>, LogError> {
+    s.split(';').map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.parse()).collect()
+}
+
+/// `host` without a trailing port. A bare IPv6 address, whose colons
+/// aren't a port, is left as it is.
+fn strip_port(host: &str) -> &str {
+    let i = match host.rfind(':') {
+        Some(i) if i + 1 < host.len() && host[i + 1..].bytes().all(|x| x.is_ascii_digit()) => i,
+        _ => return host,
+    };
+    if host.starts_with('[') {
+        if host[..i].ends_with(']') { &host[..i] } else { host }
+    } else if host[..i].contains(':') {
+        host
+    } else {
+        &host[..i]
+    }
+}
+
+/// Applies `rules` to `map` in order.
+pub fn apply(rules: &[Rule], map: &mut Map<String, Value>) {
+    for rule in rules {
+        match *rule {
+            Rule::Lower(ref field) => edit(map, field, |x| x.to_lowercase()),
+            Rule::Upper(ref field) => edit(map, field, |x| x.to_uppercase()),
+            Rule::StripPort(ref field) => edit(map, field, |x| strip_port(x).to_string()),
+            Rule::Rename(ref from, ref to) => {
+                if let Some(x) = map.remove(from) {
+                    map.insert(to.to_string(), x);
+                }
+            }
+            Rule::Remove(ref field) => {
+                map.remove(field);
+            }
+        }
+    }
+}
+
+fn edit<F: Fn(&str) -> String>(map: &mut Map<String, Value>, field: &str, f: F) {
+    if let Some(&mut Value::String(ref mut x)) = map.get_mut(field) {
+        *x = f(x);
+    }
+}
+
+#[test]
+fn parse_test() {
+    assert_eq!(parse(" lower(method);strip_port( host ) ; rename(a, b);").unwrap(), vec![
+        Rule::Lower("method".to_string()),
+        Rule::StripPort("host".to_string()),
+        Rule::Rename("a".to_string(), "b".to_string()),
+    ]);
+    assert!(parse("").unwrap().is_empty());
+
+    assert_eq!(parse("lower(method); titlecase(path)").unwrap_err().to_string(), "invalid log format: unknown transform function titlecase");
+    assert!(parse("rename(a)").is_err());
+    assert!(parse("remove(a, b)").is_err());
+    assert!(parse("lower(method").is_err());
+    assert!(parse("lower()").is_err());
+    assert!(parse("lower").is_err());
+}
+
+#[test]
+fn strip_port_test() {
+    assert_eq!(strip_port("7.248.7.119:8080"), "7.248.7.119");
+    assert_eq!(strip_port("example.com:443"), "example.com");
+    assert_eq!(strip_port("[2001:db8::1]:443"), "[2001:db8::1]");
+    assert_eq!(strip_port("2001:db8::1"), "2001:db8::1");
+    assert_eq!(strip_port("7.248.7.119"), "7.248.7.119");
+    assert_eq!(strip_port("a:b"), "a:b");
+    assert_eq!(strip_port("a:"), "a:");
+}
+
+#[test]
+fn transform_rules_config_test() {
+    use config::Config;
+
+    let config = |rules: &str| {
+        let rules = rules.to_string();
+        Config::from_vars(move |key| match key {
+            "TRANSFORM_RULES" => Some(rules.clone()),
+            _ => None,
+        })
+    };
+    assert_eq!(config("upper(method)").unwrap().transform_rules, vec![Rule::Upper("method".to_string())]);
+    assert_eq!(config("lower(method); explode(host)").err().unwrap().kind(), "FormatError");
+}
@@ -0,0 +1,634 @@
+//! S3 object-created trigger: each new object is read line by line, parsed,
+//! and written back in `OUTPUT_FORMAT` under `transformed/`.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader};
+use std::mem;
+
+use flate2::read::MultiGzDecoder;
+use rusoto_core::Region;
+use rusoto_s3::{AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart,
+                CreateMultipartUploadRequest, GetObjectRequest, PutObjectRequest, S3, S3Client, UploadPartRequest};
+use serde_derive::{Serialize, Deserialize};
+use serde_json;
+use tokio::runtime::{Builder, Runtime};
+use tokio_util::io::SyncIoBridge;
+
+use {access_log2json, decode_component, decode_text, parse_access_log, prepend_fields, should_drop, stream_fields, trim_line,
+     LogError, Ndjson};
+use config::{Config, OutputFormat, Parser};
+
+/// Prefix the transformed copy of an object is written under. Objects that
+/// already live here are ignored, so a trigger on the whole bucket doesn't
+/// loop.
+pub static OUTPUT_PREFIX: &str = "transformed/";
+
+/// Read buffer for object bodies; the body is never held in memory as a
+/// whole.
+static READ_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Output buffered before it is sent as one part of a multipart upload;
+/// S3 wants at least 5 MiB in every part but the last.
+static UPLOAD_PART_BYTES: usize = 8 * 1024 * 1024;
+
+/// Where objects are read from and written to, so the handler can run
+/// against a mock in tests.
+pub trait ObjectStore {
+    fn get(&self, bucket: &str, key: &str) -> Result<Box<dyn BufRead>, LogError>;
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), LogError>;
+    /// Starts an upload of `key` that is sent part by part.
+    fn upload<'s>(&'s self, bucket: &str, key: &str, content_type: &str) -> Result<Box<dyn Upload + 's>, LogError>;
+}
+
+/// An object being uploaded in parts, which only appears once completed.
+pub trait Upload {
+    fn write_part(&mut self, part: Vec<u8>) -> Result<(), LogError>;
+    fn complete(self: Box<Self>) -> Result<(), LogError>;
+    /// Drops the parts sent so far, which S3 would otherwise keep (and
+    /// bill for) until a lifecycle rule cleans them up.
+    fn abort(self: Box<Self>);
+}
+
+/// `ObjectStore` backed by S3, blocking on its own single-worker runtime.
+pub struct S3Store {
+    runtime: Runtime,
+    client: S3Client,
+}
+
+impl S3Store {
+    /// Connects to S3 in the region from `AWS_REGION`.
+    pub fn new() -> Result<S3Store, LogError> {
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+        let client = {
+            let _guard = runtime.enter();
+            S3Client::new(Region::default())
+        };
+        Ok(S3Store { runtime, client })
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn get(&self, bucket: &str, key: &str) -> Result<Box<dyn BufRead>, LogError> {
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        let output = self.runtime.block_on(self.client.get_object(request))
+            .map_err(|e| LogError::S3Error(e.to_string()))?;
+        let body = output.body
+            .ok_or_else(|| LogError::S3Error(format!("s3://{}/{} has no body", bucket, key)))?;
+
+        let reader = SyncIoBridge::new_with_handle(body.into_async_read(), self.runtime.handle().clone());
+        Ok(Box::new(BufReader::with_capacity(READ_BUFFER_BYTES, reader)))
+    }
+
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), LogError> {
+        let request = PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_type: Some(content_type.to_string()),
+            body: Some(body.into()),
+            ..Default::default()
+        };
+        self.runtime.block_on(self.client.put_object(request))
+            .map_err(|e| LogError::S3Error(e.to_string()))?;
+        Ok(())
+    }
+
+    fn upload<'s>(&'s self, bucket: &str, key: &str, content_type: &str) -> Result<Box<dyn Upload + 's>, LogError> {
+        let request = CreateMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_type: Some(content_type.to_string()),
+            ..Default::default()
+        };
+        let output = self.runtime.block_on(self.client.create_multipart_upload(request))
+            .map_err(|e| LogError::S3Error(e.to_string()))?;
+        let upload_id = output.upload_id
+            .ok_or_else(|| LogError::S3Error(format!("no upload id for s3://{}/{}", bucket, key)))?;
+        Ok(Box::new(S3Upload { store: self, bucket: bucket.to_string(), key: key.to_string(), upload_id, parts: Vec::new() }))
+    }
+}
+
+/// A multipart upload started by `S3Store::upload`.
+struct S3Upload<'s> {
+    store: &'s S3Store,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    parts: Vec<CompletedPart>,
+}
+
+impl<'s> Upload for S3Upload<'s> {
+    fn write_part(&mut self, part: Vec<u8>) -> Result<(), LogError> {
+        let part_number = self.parts.len() as i64 + 1;
+        let request = UploadPartRequest {
+            bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            upload_id: self.upload_id.clone(),
+            part_number,
+            content_length: Some(part.len() as i64),
+            body: Some(part.into()),
+            ..Default::default()
+        };
+        let output = self.store.runtime.block_on(self.store.client.upload_part(request))
+            .map_err(|e| LogError::S3Error(e.to_string()))?;
+        self.parts.push(CompletedPart { e_tag: output.e_tag, part_number: Some(part_number) });
+        Ok(())
+    }
+
+    fn complete(self: Box<Self>) -> Result<(), LogError> {
+        let upload = *self;
+        let request = CompleteMultipartUploadRequest {
+            bucket: upload.bucket,
+            key: upload.key,
+            upload_id: upload.upload_id,
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(upload.parts) }),
+            ..Default::default()
+        };
+        upload.store.runtime.block_on(upload.store.client.complete_multipart_upload(request))
+            .map_err(|e| LogError::S3Error(e.to_string()))?;
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) {
+        let request = AbortMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            upload_id: self.upload_id.clone(),
+            ..Default::default()
+        };
+        if let Err(e) = self.store.runtime.block_on(self.store.client.abort_multipart_upload(request)) {
+            tracing::warn!(bucket = %self.bucket, key = %self.key, "abort failed: {}", e);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3Event {
+    #[serde(rename = "Records")]
+    pub records: Vec<S3EventRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3EventRecord {
+    #[serde(rename = "awsRegion")]
+    pub aws_region: String,
+    pub s3: S3Entity,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3Entity {
+    pub bucket: S3Bucket,
+    pub object: S3Object,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3Bucket {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct S3Object {
+    /// URL-encoded, with spaces as `+`.
+    pub key: String,
+}
+
+/// Line counts for one transformed object.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ObjectResult {
+    pub bucket: String,
+    pub key: String,
+    /// Key the output was written to, or with `FANOUT` the prefix of the
+    /// per-line objects. `None` when the object was skipped or no line
+    /// survived.
+    pub output_key: Option<String>,
+    pub ok: usize,
+    pub failed: usize,
+    pub dropped: usize,
+}
+
+/// Transforms every object in the event, stopping at the first one that
+/// can't be read or written.
+pub fn transform_event(config: &Config, store: &dyn ObjectStore, event: &S3Event) -> Result<Vec<ObjectResult>, LogError> {
+    config.reset_last_format();
+    event.records.iter()
+        .map(|x| transform_object(config, store, &x.s3.bucket.name, &decode_component(&x.s3.object.key)))
+        .collect()
+}
+
+/// Streams `key` through the parser and uploads the result to
+/// `OUTPUT_PREFIX` + `key`, or with `FANOUT` each line to `fanout_key`. The
+/// output is written as in Firehose mode, and sent in parts as it grows
+/// rather than held whole. Lines that can't be decoded, parsed or written
+/// are logged and counted rather than failing the object.
+pub fn transform_object(config: &Config, store: &dyn ObjectStore, bucket: &str, key: &str) -> Result<ObjectResult, LogError> {
+    transform_object_in_parts(config, store, bucket, key, UPLOAD_PART_BYTES)
+}
+
+fn transform_object_in_parts(config: &Config, store: &dyn ObjectStore, bucket: &str, key: &str, part_bytes: usize) -> Result<ObjectResult, LogError> {
+    let mut result = ObjectResult { bucket: bucket.to_string(), key: key.to_string(), ..Default::default() };
+    if key.starts_with(OUTPUT_PREFIX) {
+        return Ok(result);
+    }
+    if config.parser != Parser::Access {
+        return Err(LogError::FormatError("S3 objects can only be read as access logs".to_string()));
+    }
+
+    let output_key = if config.fanout { format!("{}{}/", OUTPUT_PREFIX, key) } else { format!("{}{}", OUTPUT_PREFIX, key) };
+    let mut output = Output {
+        config,
+        store,
+        bucket,
+        key: &output_key,
+        fields: stream_fields(config),
+        part_bytes,
+        upload: None,
+    };
+    let written = transform_lines(config, store, bucket, key, &mut output, &mut result)
+        .and_then(|lines| output.finish(lines));
+    if let Err(e) = written {
+        output.abort();
+        return Err(e);
+    }
+
+    if result.ok > 0 {
+        result.output_key = Some(output_key.clone());
+    }
+    Ok(result)
+}
+
+/// Runs the lines of `key` into `output`, returning the unsent rest.
+fn transform_lines<'s>(config: &'s Config, store: &dyn ObjectStore, bucket: &str, key: &str, output: &mut Output<'s>, result: &mut ObjectResult) -> Result<Ndjson<'s>, LogError> {
+    let mut reader = decompress(store.get(bucket, key)?)?;
+    let mut lines = Ndjson::new(config, 0);
+    let mut buf = Vec::new();
+    while reader.read_until(b'\n', &mut buf)? > 0 {
+        let text = match decode_text(config, mem::take(&mut buf)) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::warn!(bucket, key, error_type = e.kind(), "line failed: {}", e);
+                result.failed += 1;
+                continue;
+            }
+        };
+        let line = trim_line(&text);
+        if line.is_empty() {
+            continue;
+        }
+
+        let written = match parse_access_log(config, line) {
+            Ok(ref log) if should_drop(config, log) => {
+                result.dropped += 1;
+                continue;
+            }
+            Ok(log) => access_log2json(config, &log).and_then(|x| lines.push(&x, BTreeMap::new)),
+            Err(e) => Err(e),
+        };
+        match written {
+            Ok(()) => {
+                output.write(&mut lines, result.ok)?;
+                result.ok += 1;
+            }
+            Err(e) => {
+                tracing::warn!(bucket, key, error_type = e.kind(), "line failed: {}", e);
+                result.failed += 1;
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// Where an object's output goes: with `FANOUT` an object per line, else
+/// a single put while it fits in one part and a multipart upload once it
+/// outgrows that.
+struct Output<'s> {
+    config: &'s Config,
+    store: &'s dyn ObjectStore,
+    bucket: &'s str,
+    key: &'s str,
+    fields: serde_json::Map<String, serde_json::Value>,
+    part_bytes: usize,
+    upload: Option<Box<dyn Upload + 's>>,
+}
+
+impl<'s> Output<'s> {
+    fn content_type(&self) -> &'static str {
+        match self.config.output_format {
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Json => "application/x-ndjson",
+        }
+    }
+
+    /// `data` with the `RECORD_ID_PREFIX` fields added to its lines.
+    fn chunk(&self, data: Vec<u8>) -> Result<Vec<u8>, LogError> {
+        if self.fields.is_empty() || self.config.output_format != OutputFormat::Json {
+            return Ok(data);
+        }
+        prepend_fields(&data, &self.fields)
+    }
+
+    /// Sends what `lines` holds after its `n`th line was pushed, once there
+    /// is a part's worth; each fanned-out line starts a new writer, so that
+    /// every object gets its own CSV header.
+    fn write(&mut self, lines: &mut Ndjson<'s>, n: usize) -> Result<(), LogError> {
+        if self.config.fanout {
+            let line = mem::replace(lines, Ndjson::new(self.config, 0));
+            let body = self.chunk(line.data)?;
+            return self.store.put(self.bucket, &fanout_key(self.key, n), body, self.content_type());
+        }
+        if lines.data.len() < self.part_bytes {
+            return Ok(());
+        }
+        let part = self.chunk(lines.take_data())?;
+        if self.upload.is_none() {
+            self.upload = Some(self.store.upload(self.bucket, self.key, self.content_type())?);
+        }
+        self.upload.as_mut().map_or(Ok(()), |x| x.write_part(part))
+    }
+
+    /// Sends the rest of `lines` and completes the object.
+    fn finish(&mut self, mut lines: Ndjson) -> Result<(), LogError> {
+        if self.config.fanout || lines.lines == 0 {
+            return Ok(());
+        }
+        let rest = self.chunk(lines.take_data())?;
+        match self.upload.take() {
+            None => self.store.put(self.bucket, self.key, rest, self.content_type()),
+            Some(mut upload) => {
+                if !rest.is_empty() {
+                    if let Err(e) = upload.write_part(rest) {
+                        upload.abort();
+                        return Err(e);
+                    }
+                }
+                upload.complete()
+            }
+        }
+    }
+
+    fn abort(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            upload.abort();
+        }
+    }
+}
+
+/// Key of the `n`th kept line under `prefix`, zero-padded so that listing
+/// the prefix returns the lines in order.
+fn fanout_key(prefix: &str, n: usize) -> String {
+    format!("{}{:08}.json", prefix, n)
+}
+
+/// Wraps gzip bodies, detected by their magic bytes, in a decoder.
+fn decompress(mut reader: Box<dyn BufRead>) -> Result<Box<dyn BufRead>, LogError> {
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))));
+    }
+    Ok(reader)
+}
+
+#[cfg(test)]
+struct MockStore {
+    objects: ::std::cell::RefCell<BTreeMap<(String, String), Vec<u8>>>,
+    /// Part sizes of each completed multipart upload, by key.
+    uploads: ::std::cell::RefCell<BTreeMap<String, Vec<usize>>>,
+}
+
+#[cfg(test)]
+impl MockStore {
+    fn new(objects: &[(&str, &str, &[u8])]) -> MockStore {
+        MockStore {
+            objects: ::std::cell::RefCell::new(objects.iter()
+                .map(|&(bucket, key, body)| ((bucket.to_string(), key.to_string()), body.to_vec()))
+                .collect()),
+            uploads: ::std::cell::RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn body(&self, bucket: &str, key: &str) -> Option<String> {
+        self.objects.borrow()
+            .get(&(bucket.to_string(), key.to_string()))
+            .map(|x| String::from_utf8(x.clone()).unwrap())
+    }
+}
+
+#[cfg(test)]
+impl ObjectStore for MockStore {
+    fn get(&self, bucket: &str, key: &str) -> Result<Box<dyn BufRead>, LogError> {
+        let body = self.objects.borrow()
+            .get(&(bucket.to_string(), key.to_string()))
+            .cloned()
+            .ok_or_else(|| LogError::S3Error(format!("NoSuchKey: {}", key)))?;
+        Ok(Box::new(::std::io::Cursor::new(body)))
+    }
+
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>, _content_type: &str) -> Result<(), LogError> {
+        self.objects.borrow_mut().insert((bucket.to_string(), key.to_string()), body);
+        Ok(())
+    }
+
+    fn upload<'s>(&'s self, bucket: &str, key: &str, _content_type: &str) -> Result<Box<dyn Upload + 's>, LogError> {
+        Ok(Box::new(MockUpload { store: self, bucket: bucket.to_string(), key: key.to_string(), parts: Vec::new() }))
+    }
+}
+
+#[cfg(test)]
+struct MockUpload<'s> {
+    store: &'s MockStore,
+    bucket: String,
+    key: String,
+    parts: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl<'s> Upload for MockUpload<'s> {
+    fn write_part(&mut self, part: Vec<u8>) -> Result<(), LogError> {
+        self.parts.push(part);
+        Ok(())
+    }
+
+    fn complete(self: Box<Self>) -> Result<(), LogError> {
+        self.store.uploads.borrow_mut().insert(self.key.clone(), self.parts.iter().map(|x| x.len()).collect());
+        self.store.objects.borrow_mut().insert((self.bucket.clone(), self.key.clone()), self.parts.concat());
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) {}
+}
+
+#[cfg(test)]
+static S3_EVENT: &str = r#"{
+  "Records": [
+    {
+      "eventVersion": "2.1",
+      "eventSource": "aws:s3",
+      "awsRegion": "ap-northeast-1",
+      "eventTime": "2017-12-14T13:16:50.000Z",
+      "eventName": "ObjectCreated:Put",
+      "s3": {
+        "s3SchemaVersion": "1.0",
+        "bucket": {
+          "name": "access-logs",
+          "arn": "arn:aws:s3:::access-logs"
+        },
+        "object": {
+          "key": "web/access+log.2017-12-14",
+          "size": 150
+        }
+      }
+    }
+  ]
+}"#;
+
+#[test]
+fn transform_event_test() {
+    let body = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        "garbage",
+        r#"10.0.0.1 - - [14/Dec/2017:22:16:45 +09:00] "GET /health HTTP/1.1" 200 0 "-" "ELB-HealthChecker/2.0""#,
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#,
+    ].join("\n");
+    let store = MockStore::new(&[("access-logs", "web/access log.2017-12-14", body.as_bytes())]);
+    let event: S3Event = serde_json::from_str(S3_EVENT).unwrap();
+
+    let results = transform_event(&Config::default(), &store, &event).unwrap();
+    assert_eq!(results, vec![ObjectResult {
+        bucket: "access-logs".to_string(),
+        key: "web/access log.2017-12-14".to_string(),
+        output_key: Some("transformed/web/access log.2017-12-14".to_string()),
+        ok: 2,
+        failed: 1,
+        dropped: 1,
+    }]);
+
+    let out = store.body("access-logs", "transformed/web/access log.2017-12-14").unwrap();
+    let lines: Vec<serde_json::Value> = out.split('\n').map(|x| serde_json::from_str(x).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["host"], "7.248.7.119");
+    assert_eq!(lines[1]["host"], "7.248.7.120");
+}
+
+#[test]
+fn transform_object_fanout_test() {
+    let body = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        "garbage",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#,
+    ].join("\n");
+    let store = MockStore::new(&[("access-logs", "a.log", body.as_bytes())]);
+    let config = Config::from_vars(|key| if key == "FANOUT" { Some("true".to_string()) } else { None }).unwrap();
+
+    let result = transform_object(&config, &store, "access-logs", "a.log").unwrap();
+    assert_eq!(result.ok, 2);
+    assert_eq!(result.failed, 1);
+    assert_eq!(result.output_key, Some("transformed/a.log/".to_string()));
+    assert!(store.body("access-logs", "transformed/a.log").is_none());
+    for (n, host) in ["7.248.7.119", "7.248.7.120"].iter().enumerate() {
+        let out: serde_json::Value = serde_json::from_str(&store.body("access-logs", &fanout_key("transformed/a.log/", n)).unwrap()).unwrap();
+        assert_eq!(out["host"], *host);
+    }
+    assert!(store.body("access-logs", "transformed/a.log/00000002.json").is_none());
+}
+
+#[test]
+fn transform_object_skips_output_prefix_test() {
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let store = MockStore::new(&[("access-logs", "transformed/a.log", data.as_bytes())]);
+
+    let result = transform_object(&Config::default(), &store, "access-logs", "transformed/a.log").unwrap();
+    assert_eq!(result.output_key, None);
+    assert_eq!(result.ok, 0);
+    assert!(store.body("access-logs", "transformed/transformed/a.log").is_none());
+}
+
+#[test]
+fn transform_object_gzip_test() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let data = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data.as_bytes()).unwrap();
+    let store = MockStore::new(&[("access-logs", "a.log.gz", &encoder.finish().unwrap())]);
+
+    let result = transform_object(&Config::default(), &store, "access-logs", "a.log.gz").unwrap();
+    assert_eq!(result.ok, 1);
+    let out: serde_json::Value = serde_json::from_str(&store.body("access-logs", "transformed/a.log.gz").unwrap()).unwrap();
+    assert_eq!(out["host"], "7.248.7.119");
+}
+
+#[test]
+fn transform_object_missing_test() {
+    let store = MockStore::new(&[]);
+    let err = transform_object(&Config::default(), &store, "access-logs", "a.log").unwrap_err();
+    assert_eq!(err.kind(), "S3Error");
+}
+
+#[test]
+fn transform_object_multipart_test() {
+    let body: Vec<String> = (0..5)
+        .map(|x| format!(r#"7.248.7.{} - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#, x))
+        .collect();
+    let store = MockStore::new(&[("access-logs", "a.log", body.join("\n").as_bytes())]);
+
+    let result = transform_object_in_parts(&Config::default(), &store, "access-logs", "a.log", 1).unwrap();
+    assert_eq!(result.ok, 5);
+    assert_eq!(store.uploads.borrow()["transformed/a.log"].len(), 5);
+    let out = store.body("access-logs", "transformed/a.log").unwrap();
+    let hosts: Vec<String> = out.split('\n')
+        .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()["host"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(hosts, (0..5).map(|x| format!("7.248.7.{}", x)).collect::<Vec<_>>());
+
+    let store = MockStore::new(&[("access-logs", "a.log", body.join("\n").as_bytes())]);
+    transform_object(&Config::default(), &store, "access-logs", "a.log").unwrap();
+    assert!(store.uploads.borrow().is_empty());
+    assert_eq!(store.body("access-logs", "transformed/a.log").unwrap(), out);
+}
+
+#[test]
+fn transform_object_invalid_utf8_test() {
+    let body = b"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] \"GET /caf\xe9\" 200 9947\n\
+        7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] \"GET /search\" 404 10";
+    let store = MockStore::new(&[("access-logs", "a.log", body)]);
+    let result = transform_object(&Config::default(), &store, "access-logs", "a.log").unwrap();
+    assert_eq!((result.ok, result.failed), (1, 1));
+
+    let config = Config::from_vars(|key| if key == "TEXT_ENCODING" { Some("latin1".to_string()) } else { None }).unwrap();
+    let result = transform_object(&config, &store, "access-logs", "a.log").unwrap();
+    assert_eq!((result.ok, result.failed), (2, 0));
+    let out = store.body("access-logs", "transformed/a.log").unwrap();
+    let first: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+    assert_eq!(first["path"], "/caf\u{e9}");
+}
+
+#[test]
+fn transform_object_output_format_test() {
+    let body = [
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#,
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#,
+    ].join("\n");
+    let store = MockStore::new(&[("access-logs", "a.log", body.as_bytes())]);
+    let vars = |vars: &'static [(&'static str, &'static str)]| {
+        Config::from_vars(|key| vars.iter().find(|x| x.0 == key).map(|x| x.1.to_string())).unwrap()
+    };
+
+    let config = vars(&[("OUTPUT_FORMAT", "csv"), ("CSV_HEADER", "true")]);
+    transform_object_in_parts(&config, &store, "access-logs", "a.log", 1).unwrap();
+    let out = store.body("access-logs", "transformed/a.log").unwrap();
+    let rows: Vec<&str> = out.split('\n').collect();
+    assert_eq!(rows.len(), 3);
+    assert!(rows[0].starts_with("@timestamp,host,"));
+    assert!(rows[1].contains(",7.248.7.119,"));
+
+    let config = vars(&[("RECORD_ID_PREFIX", "web")]);
+    transform_object_in_parts(&config, &store, "access-logs", "a.log", 1).unwrap();
+    let out = store.body("access-logs", "transformed/a.log").unwrap();
+    for line in out.split('\n') {
+        let line: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(line["source_stream"], "web");
+    }
+}
@@ -0,0 +1,55 @@
+use serde_derive::{Serialize, Deserialize};
+use woothee::parser::Parser;
+
+/// Browser, OS and device category derived from a `User-Agent` header.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct UserAgent<'a> {
+    pub browser: &'a str,
+    pub browser_version: &'a str,
+    pub os: &'a str,
+    /// One of woothee's categories: `pc`, `smartphone`, `mobilephone`,
+    /// `appliance`, `crawler`, `misc` or `UNKNOWN`.
+    pub category: &'a str,
+}
+
+impl<'a> UserAgent<'a> {
+    pub fn is_crawler(&self) -> bool {
+        self.category == "crawler"
+    }
+}
+
+/// Returns `None` for an empty or `-` user-agent.
+pub fn parse<'a>(ua: &'a str) -> Option<UserAgent<'a>> {
+    if ua.is_empty() || ua == "-" {
+        return None;
+    }
+
+    Parser::new().parse(ua).map(|x| UserAgent {
+        browser: x.name,
+        browser_version: x.version,
+        os: x.os,
+        category: x.category,
+    })
+}
+
+#[test]
+fn parse_firefox_test() {
+    let ua = parse("Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1").unwrap();
+
+    assert_eq!(ua.browser, "Firefox");
+    assert_eq!(ua.browser_version, "8.5.1");
+    assert_eq!(ua.os, "Windows 8");
+    assert_eq!(ua.category, "pc");
+    assert!(!ua.is_crawler());
+}
+
+#[test]
+fn parse_crawler_test() {
+    let ua = parse("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)").unwrap();
+    assert!(ua.is_crawler());
+}
+
+#[test]
+fn parse_missing_test() {
+    assert!(parse("-").is_none());
+}
@@ -0,0 +1,75 @@
+//! Small helpers shared by the enrichment steps.
+
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Runs `op` up to `attempts` times, sleeping `backoff` after the first
+/// failure and twice as long after each one since. Returns the first
+/// success, or the last error once every attempt has failed.
+pub fn retry<T, E, F>(mut op: F, attempts: u32, backoff: Duration) -> Result<T, E>
+    where F: FnMut() -> Result<T, E>
+{
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(x) => return Ok(x),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(_) => {
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Counting semaphore bounding how many threads hold a permit at once, so
+/// that enrichment on every Rayon thread doesn't oversubscribe the
+/// resource behind it.
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A held permit, given back when dropped.
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore { available: Mutex::new(permits), released: Condvar::new() }
+    }
+
+    /// Waits for a permit to be free and takes it.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            available = self.released.wait(available).unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+        Permit { semaphore: self }
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+#[test]
+fn retry_test() {
+    let mut calls = 0;
+    let result = retry(|| { calls += 1; if calls < 3 { Err(calls) } else { Ok("found") } }, 3, Duration::from_millis(1));
+    assert_eq!(result, Ok("found"));
+    assert_eq!(calls, 3);
+
+    let mut calls = 0;
+    let result: Result<(), u32> = retry(|| { calls += 1; Err(calls) }, 2, Duration::from_millis(1));
+    assert_eq!(result, Err(2));
+    assert_eq!(calls, 2);
+}
@@ -0,0 +1,204 @@
+//! W3C extended log files, as written by IIS: `#`-prefixed directives, the
+//! `#Fields:` one naming the columns of the whitespace-separated rows that
+//! follow it.
+
+use std::collections::BTreeMap;
+
+use chrono::prelude::*;
+use serde_json::{self, Map, Value};
+
+use {check_line_length, geoip, partition_keys_at, shape_output, trim_line, useragent, LogError, Ndjson, RecordError, Timestamp, Transformed};
+use OUTPUT_SIZE_RATIO;
+use config::Config;
+
+/// Columns written as JSON numbers when they parse as one.
+static NUMERIC_FIELDS: &[&str] = &[
+    "s-port", "c-port", "sc-status", "sc-substatus", "sc-win32-status", "sc-bytes", "cs-bytes", "sc-content-len",
+    "time-taken", "time-to-first-byte",
+];
+
+/// Integers as such, e.g. IIS's `time-taken` in milliseconds, and anything
+/// else numeric, e.g. CloudFront's in seconds, as a float.
+fn number(s: &str) -> Option<Value> {
+    s.parse::<u64>().map(Value::from).ok()
+        .or_else(|| s.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number))
+}
+
+/// Returns the column names of a `#Fields:` directive.
+pub fn fields_directive(line: &str) -> Option<Vec<String>> {
+    let rest = trim_line(line).strip_prefix("#Fields:")?;
+    Some(rest.split_whitespace().map(|x| x.to_string()).collect())
+}
+
+/// A data row as JSON, with its time when it has `date` and `time`.
+pub type Row = (Map<String, Value>, Option<DateTime<Utc>>);
+
+/// The error for a row that comes before any `#Fields:` directive.
+pub fn no_fields() -> LogError {
+    LogError::FormatError("no #Fields directive before data".to_string())
+}
+
+/// Maps one data row onto `fields`. `-` becomes `null`, and `date` and
+/// `time`, which W3C logs in UTC, are combined into `@timestamp` and
+/// `@timestamp_utc`.
+pub fn parse_row(config: &Config, fields: &[String], line: &str) -> Result<Row, LogError> {
+    check_line_length(config, line)?;
+    let values: Vec<&str> = trim_line(line).split_whitespace().collect();
+    if values.len() != fields.len() {
+        return Err(LogError::unmatched(line));
+    }
+
+    let mut map = Map::new();
+    for (field, &value) in fields.iter().zip(&values) {
+        let value = match value {
+            "-" => Value::Null,
+            x if NUMERIC_FIELDS.contains(&field.as_str()) => number(x).unwrap_or_else(|| Value::from(x)),
+            x => Value::from(x),
+        };
+        map.insert(field.to_string(), value);
+    }
+
+    let time = match (map.get("date").and_then(|x| x.as_str()), map.get("time").and_then(|x| x.as_str())) {
+        (Some(date), Some(time)) => {
+            let time = NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S")?.and_utc();
+            let timestamp = serde_json::to_value(Timestamp::new(config.timestamp_format, &time))?;
+            map.insert("@timestamp".to_string(), timestamp.clone());
+            map.insert("@timestamp_utc".to_string(), timestamp);
+            Some(time)
+        }
+        _ => None,
+    };
+
+    if config.anonymize_ip {
+        if let Some(x) = map.get_mut("c-ip") {
+            if let Some(ip) = x.as_str().and_then(|x| x.parse().ok()) {
+                *x = Value::from(geoip::anonymize(ip).to_string());
+            }
+        }
+    }
+    if config.retain_raw {
+        map.insert("raw".to_string(), Value::from(line));
+    }
+    Ok((map, time))
+}
+
+/// `transform_data` for W3C records. Directive lines are skipped, with
+/// `#Fields:` setting the columns for the rows after it in the same record;
+/// rows seen before any `#Fields:` fail.
+pub fn transform(config: &Config, s: &str) -> Result<Option<Transformed>, RecordError> {
+    transform_rows(config, s, |_| {})
+}
+
+/// `transform`, with `f` applied to each row before it is filtered and
+/// renamed, for dialects such as CloudFront's.
+pub fn transform_rows<F>(config: &Config, s: &str, f: F) -> Result<Option<Transformed>, RecordError>
+    where F: Fn(&mut Map<String, Value>)
+{
+    let mut fields = None;
+    let mut out = Ndjson::new(config, s.len() * OUTPUT_SIZE_RATIO);
+    for line in s.split('\n').filter(|x| !x.trim().is_empty()) {
+        if line.starts_with('#') {
+            if let Some(xs) = fields_directive(line) {
+                fields = Some(xs);
+            }
+            continue;
+        }
+
+        let fields = match fields {
+            Some(ref x) => x,
+            None => {
+                out.fail(no_fields(), line);
+                continue;
+            }
+        };
+        match parse_row(config, fields, line).map(|(mut map, time)| { f(&mut map); (map, time) }) {
+            Ok((ref map, _)) if should_drop(config, map) => {}
+            Ok((mut map, time)) => {
+                let status = status(&map);
+                shape_output(config, &mut map);
+                out.push(&Value::Object(map), || time.map(|x| partition_keys_at(&x, status)).unwrap_or_else(BTreeMap::new))?;
+            }
+            Err(error) => out.fail(error, line),
+        }
+    }
+
+    // A record of nothing but directives is not an error, only empty.
+    out.finish()
+}
+
+/// IIS writes the spaces in `cs(User-Agent)` as `+`. `DROP_PATH_REGEX`
+/// matches `cs-uri-stem`, which holds no query. Rows without `sc-status`
+/// are never dropped by `STATUS_FILTER`.
+fn should_drop(config: &Config, map: &Map<String, Value>) -> bool {
+    let crawler = config.drop_crawlers && map.get("cs(User-Agent)")
+        .and_then(|x| x.as_str())
+        .and_then(|x| useragent::parse(&x.replace('+', " ")).map(|x| x.is_crawler()))
+        .unwrap_or(false);
+    let path = config.drop_path_regex.as_ref()
+        .is_some_and(|re| map.get("cs-uri-stem").and_then(|x| x.as_str()).is_some_and(|x| re.is_match(x)));
+    crawler || path || status(map).is_some_and(|x| !config.keeps_status(x))
+}
+
+fn status(map: &Map<String, Value>) -> Option<u16> {
+    map.get("sc-status").and_then(|x| x.as_u64()).map(|x| x as u16)
+}
+
+#[cfg(test)]
+static W3C_LOG: &str = "#Software: Microsoft Internet Information Services 10.0
+#Version: 1.0
+#Date: 2017-12-14 13:16:45
+#Fields: date time s-ip cs-method cs-uri-stem cs-uri-query s-port cs-username c-ip cs(User-Agent) sc-status time-taken
+2017-12-14 13:16:45 10.0.0.5 GET /explore - 80 - 7.248.7.119 Mozilla/5.0+(Windows+NT+10.0) 200 15
+2017-12-14 13:16:46 10.0.0.5 GET /search q=rust 80 - 7.248.7.120 - 404 3
+";
+
+#[test]
+fn fields_directive_test() {
+    assert_eq!(fields_directive("#Fields: date time c-ip\r"), Some(vec!["date".to_string(), "time".to_string(), "c-ip".to_string()]));
+    assert_eq!(fields_directive("#Version: 1.0"), None);
+}
+
+#[test]
+fn transform_test() {
+    let config = Config::default();
+    let out = transform(&config, W3C_LOG).unwrap().unwrap();
+    let lines: Vec<Value> = String::from_utf8(out.data).unwrap()
+        .split('\n')
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["@timestamp"], "2017-12-14T13:16:45+00:00");
+    assert_eq!(lines[0]["cs-uri-stem"], "/explore");
+    assert_eq!(lines[0]["cs-uri-query"], Value::Null);
+    assert_eq!(lines[0]["sc-status"], 200);
+    assert_eq!(lines[1]["cs-uri-query"], "q=rust");
+    assert_eq!(out.partition_keys["status_class"], "2xx");
+
+}
+
+#[test]
+fn transform_errors_test() {
+    let config = Config::default();
+    let err = transform(&config, "2017-12-14 13:16:45 10.0.0.5 GET /").unwrap_err();
+    assert_eq!(err.error.to_string(), "invalid log format: no #Fields directive before data");
+
+    // Each record needs its own header, whatever was parsed before it.
+    assert!(transform(&config, W3C_LOG).unwrap().is_some());
+    let err = transform(&config, "2017-12-14 13:16:47 10.0.0.5 GET / - 80 - 7.248.7.121 - 200 1").unwrap_err();
+    assert_eq!(err.error.kind(), "FormatError");
+
+    let err = transform(&config, "#Fields: date time c-ip\n2017-12-14 13:16:45").unwrap_err();
+    assert_eq!(err.error.kind(), "RegexParseError");
+
+    assert!(transform(&config, "#Version: 1.0\n#Fields: date time c-ip\n").unwrap().is_none());
+}
+
+#[test]
+fn transform_drop_path_test() {
+    let config = Config::from_vars(|key| if key == "DROP_PATH_REGEX" { Some("^/search$".to_string()) } else { None }).unwrap();
+    let out = transform(&config, W3C_LOG).unwrap().unwrap();
+    let out = String::from_utf8(out.data).unwrap();
+    assert_eq!(out.lines().count(), 1);
+    assert!(out.contains("/explore"));
+}
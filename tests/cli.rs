@@ -0,0 +1,99 @@
+extern crate serde_json;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn stdin_filter_test() {
+    let input = concat!(
+        r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore" 200 9947"#, "\n",
+        "garbage\n",
+        "\n",
+        r#"7.248.7.120 - - [14/Dec/2017:22:16:46 +09:00] "GET /search" 404 10"#, "\n",
+    );
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_custom-lambda-rs"))
+        .arg("--stdin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<serde_json::Value> = stdout.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["host"], "7.248.7.119");
+    assert_eq!(lines[1]["response"], 404);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.trim(), "line 2: FAIL. unmatched pattern: garbage");
+}
+
+#[test]
+fn validate_test() {
+    let sample = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/validate.log");
+    let validate = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_custom-lambda-rs"))
+        .arg("--validate")
+        .arg(sample)
+        .args(args)
+        .output()
+        .unwrap();
+
+    let output = validate(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(&lines[..3], &["total: 5", "parsed: 3", "failed: 2"]);
+    assert_eq!(lines[3], "line 2: FAIL. unmatched pattern: garbage");
+    assert!(lines[4].starts_with("line 6: "));
+    assert_eq!(lines[5], "FAIL (failure ratio 0.4000, max 0)");
+
+    let output = validate(&["--max-failure-ratio", "0.5"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().ends_with("PASS (failure ratio 0.4000, max 0.5)\n"));
+}
+
+#[test]
+fn firehose_filter_test() {
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/");
+    let input = std::fs::read_to_string(format!("{}apache_combined.input.json", fixtures)).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(format!("{}apache_combined.expected.json", fixtures)).unwrap()).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_custom-lambda-rs"))
+        .arg("--firehose")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let response: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(response, expected);
+    let emf: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert!(emf["_aws"].is_object());
+}
+
+#[test]
+fn validate_w3c_test() {
+    let sample = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/validate_w3c.log");
+    let output = Command::new(env!("CARGO_BIN_EXE_custom-lambda-rs"))
+        .arg("--validate")
+        .arg(sample)
+        .args(["--max-failure-ratio", "0.5"])
+        .env("LOG_FLAVOR", "w3c")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(&lines[..3], &["total: 3", "parsed: 2", "failed: 1"]);
+    assert!(lines[3].starts_with("line 6: "));
+}
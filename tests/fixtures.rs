@@ -0,0 +1,38 @@
+//! Golden tests: each `tests/fixtures/<name>.input.json` Firehose event is
+//! run through `transform_event` as the Lambda handler would run it, and
+//! the response compared with `<name>.expected.json`. Set `UPDATE_FIXTURES`
+//! to rewrite the expected files from the current output instead.
+
+extern crate custom_lambda_rs;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use custom_lambda_rs::config::Config;
+use custom_lambda_rs::FirehoseEvent;
+
+#[test]
+fn firehose_fixtures_test() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut inputs: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.to_string_lossy().ends_with(".input.json"))
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no fixtures in {}", dir.display());
+
+    for input in inputs {
+        let expected_path = input.with_file_name(input.file_name().unwrap().to_string_lossy().replace(".input.json", ".expected.json"));
+        let event: FirehoseEvent = serde_json::from_str(&fs::read_to_string(&input).unwrap()).unwrap();
+        let actual = serde_json::to_value(custom_lambda_rs::transform_event(&Config::default(), &event)).unwrap();
+
+        if env::var_os("UPDATE_FIXTURES").is_some() {
+            fs::write(&expected_path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+            continue;
+        }
+        let expected: serde_json::Value = serde_json::from_str(&fs::read_to_string(&expected_path).unwrap()).unwrap();
+        assert_eq!(actual, expected, "{}", input.display());
+    }
+}
@@ -0,0 +1,46 @@
+extern crate custom_lambda_rs;
+extern crate serde_json;
+
+use custom_lambda_rs::config::Config;
+
+static LINE: &str = r#"7.248.7.119 - - [14/Dec/2017:22:16:45 +09:00] "GET /explore?q=rust HTTP/1.1" 200 9947 "-" "Mozilla/5.0 (Windows NT 6.2; WOW64; rv:8.5) Gecko/20100101 Firefox/8.5.1""#;
+
+#[test]
+fn parse_access_log_test() {
+    let log = custom_lambda_rs::parse_access_log(&Config::default(), LINE).unwrap();
+
+    assert_eq!(log.host, "7.248.7.119");
+    assert_eq!(log.method, "GET");
+    assert_eq!(log.path.as_deref(), Some("/explore?q=rust"));
+    assert_eq!(log.query["q"], "rust");
+    assert_eq!(log.response, 200);
+    assert_eq!(log.bytes, Some(9947));
+    assert_eq!(log.ua.map(|x| x.browser), Some("Firefox"));
+}
+
+#[test]
+fn log2json_test() {
+    let a = custom_lambda_rs::log2json(&Config::default(), LINE).unwrap();
+
+    assert_eq!(a["host"], "7.248.7.119");
+    assert_eq!(a["@timestamp"], "2017-12-14T22:16:45+09:00");
+    assert_eq!(a["@timestamp_utc"], "2017-12-14T13:16:45+00:00");
+    assert!(custom_lambda_rs::log2json(&Config::default(), "garbage").is_err());
+}
+
+#[test]
+fn transform_data_test() {
+    let data = format!("{}\ngarbage\n{}", LINE, LINE).into_bytes();
+    let out = custom_lambda_rs::transform_data(&Config::default(), data).unwrap().unwrap();
+
+    let lines: Vec<serde_json::Value> = String::from_utf8(out.data).unwrap()
+        .split('\n')
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["host"], "7.248.7.119");
+    assert_eq!(out.partition_keys["status_class"], "2xx");
+
+    let err = custom_lambda_rs::transform_data(&Config::default(), b"garbage".to_vec()).unwrap_err();
+    assert_eq!(err.line, Some("garbage".to_string()));
+}
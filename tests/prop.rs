@@ -0,0 +1,151 @@
+//! Generated Apache lines, Common and Combined, must all parse back to the
+//! fields they were built from, and `clf::scan` must agree with the regex
+//! it stands in for. Failing cases are shrunk, so the reported line is a
+//! minimal one.
+
+extern crate chrono;
+extern crate custom_lambda_rs;
+extern crate proptest;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::OnceLock;
+
+use chrono::{DateTime, FixedOffset, TimeZone};
+use custom_lambda_rs::clf;
+use custom_lambda_rs::config::Config;
+use proptest::prelude::*;
+
+#[derive(Debug, Clone)]
+struct Line {
+    host: IpAddr,
+    authuser: Option<String>,
+    time: DateTime<FixedOffset>,
+    colon_offset: bool,
+    method: &'static str,
+    path: String,
+    protocol: Option<&'static str>,
+    status: u16,
+    bytes: Option<u64>,
+    combined: Option<(String, String)>,
+}
+
+impl Line {
+    fn render(&self) -> String {
+        let time = self.time.format(if self.colon_offset { "%d/%b/%Y:%H:%M:%S %:z" } else { "%d/%b/%Y:%H:%M:%S %z" });
+        let request = match self.protocol {
+            Some(protocol) => format!("{} {} {}", self.method, self.path, protocol),
+            None => format!("{} {}", self.method, self.path),
+        };
+        let mut line = format!(
+            r#"{} - {} [{}] "{}" {} {}"#,
+            self.host,
+            self.authuser.as_deref().unwrap_or("-"),
+            time,
+            request,
+            self.status,
+            self.bytes.map(|x| x.to_string()).unwrap_or_else(|| "-".to_string()),
+        );
+        if let Some((ref referer, ref user_agent)) = self.combined {
+            line.push_str(&format!(r#" "{}" "{}""#, referer, user_agent));
+        }
+        line
+    }
+}
+
+fn host() -> impl Strategy<Value = IpAddr> {
+    prop_oneof![
+        any::<[u8; 4]>().prop_map(|x| IpAddr::V4(Ipv4Addr::from(x))),
+        any::<[u16; 8]>().prop_map(|x| IpAddr::V6(Ipv6Addr::from(x))),
+    ]
+}
+
+/// Any second from 1970 to 2100, at any quarter-hour offset in use.
+fn time() -> impl Strategy<Value = DateTime<FixedOffset>> {
+    (0i64..4_102_444_800, -48i32..=56).prop_map(|(secs, quarters)| {
+        FixedOffset::east_opt(quarters * 15 * 60).unwrap().timestamp_opt(secs, 0).unwrap()
+    })
+}
+
+fn line() -> impl Strategy<Value = Line> {
+    (
+        host(),
+        proptest::option::of("[a-z][a-z0-9._]{0,11}"),
+        time(),
+        any::<bool>(),
+        prop::sample::select(vec!["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH"]),
+        "/[a-zA-Z0-9/._~-]{0,30}(\\?[a-z]{1,5}=[a-z0-9]{0,5}){0,1}",
+        prop::sample::select(vec![None, Some("HTTP/1.0"), Some("HTTP/1.1"), Some("HTTP/2.0")]),
+        100u16..600,
+        proptest::option::of(0u64..10_000_000_000),
+        proptest::option::of(("-|https?://[a-z]{1,10}\\.com/[a-z]{0,10}", "[a-zA-Z0-9 ()/;:.,_-]{0,40}")),
+    ).prop_map(|(host, authuser, time, colon_offset, method, path, protocol, status, bytes, combined)| Line {
+        host, authuser, time, colon_offset, method, path, protocol, status, bytes, combined,
+    })
+}
+
+proptest! {
+    #[test]
+    fn generated_lines_round_trip_test(line in line()) {
+        let raw = line.render();
+        let log = custom_lambda_rs::parse_access_log(&Config::default(), &raw)
+            .map_err(|e| TestCaseError::fail(format!("{}: {}", e, raw)))?;
+
+        prop_assert_eq!(log.host, line.host.to_string());
+        prop_assert_eq!(log.ident, None);
+        prop_assert_eq!(log.authuser, line.authuser.as_deref());
+        prop_assert_eq!(log.time, line.time);
+        prop_assert_eq!(log.method, line.method);
+        prop_assert_eq!(log.path.as_deref(), Some(line.path.as_str()));
+        prop_assert_eq!(log.protocol, line.protocol);
+        prop_assert_eq!(log.response, line.status);
+        prop_assert_eq!(log.bytes, line.bytes);
+        prop_assert_eq!(log.referer, line.combined.as_ref().map(|x| x.0.as_str()).filter(|&x| x != "-"));
+        prop_assert_eq!(log.user_agent, line.combined.as_ref().map(|x| x.1.as_str()).filter(|&x| x != "-"));
+    }
+}
+
+/// Lines in the shape of the Apache pattern, with quotes, spaces and
+/// status-like text in the free-text columns, and some that don't match.
+fn clf_like_line() -> impl Strategy<Value = String> {
+    (
+        prop::sample::select(vec!["7.248.7.119", "2001:db8::1", "[::1]", "crawl-66-249-66-1.googlebot.com", "-", ""]),
+        prop::sample::select(vec!["-", "ident"]),
+        prop::sample::select(vec!["-", "frank", "a\tb"]),
+        prop::sample::select(vec!["14/Dec/2017:22:16:45 +09:00", "14/Dec/2017:22:16:45 +0900", "14/Dec/2017:22:16:45", "14/Dec/2017:22:16:45.123 -0700", "", "14/Dec/2017:22:16:45 +9:00", "x y"]),
+        prop::sample::select(vec!["GET /explore HTTP/1.1", "GET /explore", "", "\"", "GET /a\" 200 1", "GET /a\" 20 x\" HTTP/1.0", "GET /a\\\" 200 1", "GET /a\\\\\" 200 1", "GET \\", "- - -", "GET /explore?q=a%20b&x=1"]),
+        "[ \"\\\\0-9ab-]{0,12}",
+        prop::sample::select(vec!["200", "404", "500", "999", "20"]),
+        prop::sample::select(vec!["9947", "0", "-", "x"]),
+        prop::sample::select(vec!["", " \"-\" \"-\"", " \"http://example.com/\" \"Mozilla/5.0 (Windows NT 10.0)\"", " \"a\" \"b", " \"\" \"\" extra", "  \"a\" \"b\""]),
+    ).prop_map(|(host, ident, authuser, time, request, noise, status, bytes, tail)| {
+        format!("{} {} {} [{}] \"{}{}\" {} {}{}", host, ident, authuser, time, request, noise, status, bytes, tail)
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2000))]
+
+    #[test]
+    fn scan_matches_regex_test(line in clf_like_line()) {
+        static CONFIGS: OnceLock<(Config, Config)> = OnceLock::new();
+        let (scanned, regex_only) = CONFIGS.get_or_init(|| {
+            let mut regex_only = Config::default();
+            regex_only.formats[0].scanner = false;
+            (Config::default(), regex_only)
+        });
+        prop_assert!(scanned.formats[0].scanner);
+
+        let pattern = &regex_only.formats[0].pattern;
+        let expected = pattern.captures(&line);
+        let actual = clf::scan(&line);
+        prop_assert_eq!(actual.is_some(), expected.is_some(), "{}", line);
+        if let (Some(actual), Some(expected)) = (actual, expected) {
+            for name in pattern.capture_names().flatten() {
+                prop_assert_eq!(actual.name(name), expected.name(name).map(|x| x.as_str()), "{} in {}", name, line);
+            }
+        }
+
+        let json = |config: &Config| custom_lambda_rs::log2json(config, &line).map(|x| x.to_string()).map_err(|e| e.to_string());
+        prop_assert_eq!(json(scanned), json(regex_only), "{}", line);
+    }
+}